@@ -0,0 +1,106 @@
+use crate::bans::Bans;
+use crate::rank::Ranks;
+use crate::whitelist::Whitelist;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::net::IpAddr;
+
+/// Decides who may join the server and with which rank.
+pub struct Access {
+    pub salt: String, // used by clients to prove their name
+    pub ranks: Ranks,
+    pub bans: Bans,
+    pub whitelist: Whitelist,
+}
+
+impl Access {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Access {
+            salt: generate_salt(),
+            ranks: Ranks::new()?,
+            bans: Bans::new()?,
+            whitelist: Whitelist::new()?,
+        })
+    }
+}
+
+/// Generates the random salt shared with the server list, players prove their name with it.
+fn generate_salt() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .collect()
+}
+
+/// Checks the verification key sent by the client equals md5(salt + username).
+pub fn verify_name(salt: &str, username: &str, key: &str) -> bool {
+    let digest = md5::compute(format!("{}{}", salt, username));
+    let expected = format!("{:x}", digest);
+
+    // Some clients drop the leading zeros of the hash
+    let key = key.trim_end_matches(&[' ', '\0'][..]);
+    format!("{:0>32}", key).eq_ignore_ascii_case(&expected)
+}
+
+/// Returns true if the address is in the bypass list, entries are single IPs or CIDR ranges.
+pub fn is_bypassed(addr: IpAddr, bypass: &[String]) -> bool {
+    bypass.iter().any(|range| in_range(addr, range))
+}
+
+/// Returns true if the range is an IP or a CIDR range with a prefix that fits the address.
+pub fn valid_range(range: &str) -> bool {
+    let mut parts = range.trim().splitn(2, '/');
+    let max_prefix = match parts.next().map(str::parse::<IpAddr>) {
+        Some(Ok(IpAddr::V4(_))) => 32,
+        Some(Ok(IpAddr::V6(_))) => 128,
+        _ => return false,
+    };
+    match parts.next().map(str::parse::<u32>) {
+        Some(Ok(prefix)) => prefix <= max_prefix,
+        Some(Err(_)) => false,
+        None => true,
+    }
+}
+
+/// Returns true if the address is the IP or inside the CIDR range.
+pub fn in_range(addr: IpAddr, range: &str) -> bool {
+    let mut parts = range.trim().splitn(2, '/');
+    let ip = match parts.next().map(str::parse::<IpAddr>) {
+        Some(Ok(ip)) => ip,
+        _ => return false,
+    };
+    let prefix = match parts.next().map(str::parse::<u32>) {
+        Some(Ok(prefix)) => Some(prefix),
+        Some(Err(_)) => return false,
+        None => None,
+    };
+
+    match (addr, ip) {
+        (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_ranges() {
+        assert!(valid_range("10.0.0.1"));
+        assert!(valid_range("10.0.0.0/8"));
+        assert!(valid_range("fe80::/10"));
+        assert!(!valid_range("10.0.0.0/33"));
+        assert!(!valid_range("10.0.0.0/x"));
+        assert!(!valid_range("10.0.0/8"));
+    }
+}
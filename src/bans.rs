@@ -0,0 +1,172 @@
+use crate::auth;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BANS_PATH: &str = "bans.yaml";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ban {
+    pub reason: String,
+    pub by: String,
+    pub expires: Option<u64>, // unix time in seconds, permanent if empty
+}
+
+impl Ban {
+    pub fn new(reason: String, by: String, duration: Option<Duration>) -> Self {
+        Ban {
+            reason,
+            by,
+            expires: duration.map(|d| now().saturating_add(d.as_secs())),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now(),
+            None => false,
+        }
+    }
+
+    /// Kick message shown to the banned player.
+    pub fn message(&self) -> String {
+        match self.expires {
+            Some(expires) => format!(
+                "Banned for {}: {}",
+                format_duration(expires.saturating_sub(now())),
+                self.reason
+            ),
+            None => format!("Banned: {}", self.reason),
+        }
+    }
+}
+
+/// Banned names and IPs stored in `bans.yaml`, IPs may also be CIDR ranges.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Bans {
+    pub names: BTreeMap<String, Ban>,
+    pub ips: BTreeMap<String, Ban>,
+}
+
+impl Bans {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut bans = Bans::default();
+        match std::fs::read_to_string(BANS_PATH) {
+            Ok(read_str) => {
+                bans = serde_yaml::from_str(&read_str)?;
+            }
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    bans.save()?;
+                } else {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(bans)
+    }
+
+    /// Writes the file, dropping bans that ran out.
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        self.names.retain(|_, ban| !ban.expired());
+        self.ips.retain(|_, ban| !ban.expired());
+        let wr_str = serde_yaml::to_string(self)?;
+        std::fs::write(BANS_PATH, wr_str)?;
+        Ok(())
+    }
+
+    pub fn find_name(&self, name: &str) -> Option<&Ban> {
+        self.names
+            .iter()
+            .find(|(banned, ban)| banned.eq_ignore_ascii_case(name) && !ban.expired())
+            .map(|(_, ban)| ban)
+    }
+
+    pub fn find_ip(&self, addr: IpAddr) -> Option<&Ban> {
+        self.ips
+            .iter()
+            .find(|(range, ban)| auth::in_range(addr, range) && !ban.expired())
+            .map(|(_, ban)| ban)
+    }
+
+    pub fn ban_name(&mut self, name: &str, ban: Ban) -> anyhow::Result<()> {
+        self.names
+            .retain(|banned, _| !banned.eq_ignore_ascii_case(name));
+        self.names.insert(name.to_string(), ban);
+        self.save()
+    }
+
+    pub fn ban_ip(&mut self, range: &str, ban: Ban) -> anyhow::Result<()> {
+        if !auth::valid_range(range) {
+            return Err(anyhow::anyhow!("{} is not an IP or CIDR range", range));
+        }
+        self.ips.insert(range.to_string(), ban);
+        self.save()
+    }
+
+    /// Lifts the ban of a name or IP, returns false if there was none.
+    pub fn unban(&mut self, target: &str) -> anyhow::Result<bool> {
+        let count = self.names.len() + self.ips.len();
+        self.names
+            .retain(|banned, _| !banned.eq_ignore_ascii_case(target));
+        self.ips.remove(target);
+        let removed = self.names.len() + self.ips.len() < count;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Parses durations like `30s`, `15m`, `12h`, `7d` or `2w`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.len().checked_sub(1)?;
+    if !text.is_char_boundary(split) {
+        return None;
+    }
+    let (amount, unit) = text.split_at(split);
+    let amount = amount.parse::<u64>().ok()?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(secs)?))
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m", s.div_ceil(60)),
+        s if s < 24 * 60 * 60 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_bans_do_not_overflow() {
+        let ban = Ban::new(
+            String::new(),
+            String::new(),
+            Some(Duration::from_secs(u64::MAX)),
+        );
+        assert_eq!(ban.expires, Some(u64::MAX));
+        assert!(!ban.expired());
+    }
+}
@@ -0,0 +1,273 @@
+pub const AIR: u8 = 0;
+pub const STONE: u8 = 1;
+pub const GRASS: u8 = 2;
+pub const DIRT: u8 = 3;
+pub const COBBLESTONE: u8 = 4;
+pub const PLANKS: u8 = 5;
+pub const SAPLING: u8 = 6;
+pub const BEDROCK: u8 = 7;
+pub const WATER: u8 = 8;
+pub const STILL_WATER: u8 = 9;
+pub const LAVA: u8 = 10;
+pub const STILL_LAVA: u8 = 11;
+pub const SAND: u8 = 12;
+pub const GRAVEL: u8 = 13;
+pub const GOLD_ORE: u8 = 14;
+pub const IRON_ORE: u8 = 15;
+pub const COAL_ORE: u8 = 16;
+pub const LOG: u8 = 17;
+pub const LEAVES: u8 = 18;
+pub const SPONGE: u8 = 19;
+pub const GLASS: u8 = 20;
+pub const RED_WOOL: u8 = 21;
+pub const ORANGE_WOOL: u8 = 22;
+pub const LIME_WOOL: u8 = 24;
+pub const GREEN_WOOL: u8 = 25;
+pub const AQUA_WOOL: u8 = 27;
+pub const CYAN_WOOL: u8 = 28;
+pub const BLUE_WOOL: u8 = 29;
+pub const PINK_WOOL: u8 = 33;
+pub const BLACK_WOOL: u8 = 34;
+pub const GRAY_WOOL: u8 = 35;
+pub const WHITE_WOOL: u8 = 36;
+pub const DANDELION: u8 = 37;
+pub const ROSE: u8 = 38;
+pub const BROWN_MUSHROOM: u8 = 39;
+pub const RED_MUSHROOM: u8 = 40;
+pub const GOLD: u8 = 41;
+pub const IRON: u8 = 42;
+pub const DOUBLE_SLAB: u8 = 43;
+pub const SLAB: u8 = 44;
+pub const BRICK: u8 = 45;
+pub const TNT: u8 = 46;
+pub const BOOKSHELF: u8 = 47;
+pub const MOSSY_COBBLESTONE: u8 = 48;
+pub const OBSIDIAN: u8 = 49;
+
+// Added by the CustomBlocks extension
+pub const COBBLESTONE_SLAB: u8 = 50;
+pub const ROPE: u8 = 51;
+pub const SANDSTONE: u8 = 52;
+pub const SNOW: u8 = 53;
+pub const FIRE: u8 = 54;
+pub const LIGHT_PINK_WOOL: u8 = 55;
+pub const FOREST_GREEN_WOOL: u8 = 56;
+pub const BROWN_WOOL: u8 = 57;
+pub const DEEP_BLUE_WOOL: u8 = 58;
+pub const TURQUOISE_WOOL: u8 = 59;
+pub const ICE: u8 = 60;
+pub const CERAMIC_TILE: u8 = 61;
+pub const MAGMA: u8 = 62;
+pub const PILLAR: u8 = 63;
+pub const CRATE: u8 = 64;
+pub const STONE_BRICK: u8 = 65;
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub id: u8,
+    pub name: String,
+    pub solid: bool,           // players collide with it
+    pub liquid: bool,          // flows and can be swum in
+    pub gravity: bool,         // falls when nothing is below
+    pub transmits_light: bool, // blocks below stay lit
+    pub placeable: bool,       // players may place it by hand
+    pub fallback: u8,          // sent to clients lacking the extension that adds the block
+}
+
+impl Block {
+    /// A plain solid, opaque block clients always know.
+    fn classic(id: u8, name: &str) -> Self {
+        Block {
+            id,
+            name: name.to_string(),
+            solid: true,
+            liquid: false,
+            gravity: false,
+            transmits_light: false,
+            placeable: true,
+            fallback: id,
+        }
+    }
+
+    /// A block players walk through that lets light through, like flowers.
+    fn sprite(id: u8, name: &str) -> Self {
+        Block {
+            solid: false,
+            transmits_light: true,
+            ..Block::classic(id, name)
+        }
+    }
+
+    fn liquid(id: u8, name: &str) -> Self {
+        Block {
+            solid: false,
+            liquid: true,
+            ..Block::classic(id, name)
+        }
+    }
+
+    /// A CustomBlocks block, clients without the extension see the fallback instead.
+    fn custom(id: u8, name: &str, fallback: u8) -> Self {
+        Block {
+            fallback,
+            ..Block::classic(id, name)
+        }
+    }
+}
+
+/// Properties of every block the server knows, indexed by block id.
+pub struct BlockRegistry {
+    blocks: Vec<Option<Block>>,
+}
+
+impl BlockRegistry {
+    /// Registry holding the blocks of classic 0.30, ids 0 to 49, and the CustomBlocks ones up to 65.
+    pub fn new() -> Self {
+        let mut registry = BlockRegistry {
+            blocks: vec![None; 256],
+        };
+
+        registry.register(Block {
+            placeable: false,
+            ..Block::sprite(AIR, "Air")
+        });
+        registry.register(Block::classic(STONE, "Stone"));
+        registry.register(Block::classic(GRASS, "Grass"));
+        registry.register(Block::classic(DIRT, "Dirt"));
+        registry.register(Block::classic(COBBLESTONE, "Cobblestone"));
+        registry.register(Block::classic(PLANKS, "Planks"));
+        registry.register(Block::sprite(SAPLING, "Sapling"));
+        registry.register(Block::classic(BEDROCK, "Bedrock"));
+        registry.register(Block::liquid(WATER, "Water"));
+        registry.register(Block::liquid(STILL_WATER, "Still water"));
+        registry.register(Block::liquid(LAVA, "Lava"));
+        registry.register(Block::liquid(STILL_LAVA, "Still lava"));
+        registry.register(Block {
+            gravity: true,
+            ..Block::classic(SAND, "Sand")
+        });
+        registry.register(Block {
+            gravity: true,
+            ..Block::classic(GRAVEL, "Gravel")
+        });
+        registry.register(Block::classic(GOLD_ORE, "Gold ore"));
+        registry.register(Block::classic(IRON_ORE, "Iron ore"));
+        registry.register(Block::classic(COAL_ORE, "Coal ore"));
+        registry.register(Block::classic(LOG, "Log"));
+        registry.register(Block {
+            transmits_light: true,
+            ..Block::classic(LEAVES, "Leaves")
+        });
+        registry.register(Block::classic(SPONGE, "Sponge"));
+        registry.register(Block {
+            transmits_light: true,
+            ..Block::classic(GLASS, "Glass")
+        });
+
+        let wools = [
+            "Red", "Orange", "Yellow", "Lime", "Green", "Teal", "Aqua", "Cyan", "Blue", "Indigo",
+            "Violet", "Magenta", "Pink", "Black", "Gray", "White",
+        ];
+        for (id, color) in (RED_WOOL..=WHITE_WOOL).zip(wools.iter()) {
+            registry.register(Block::classic(id, &format!("{} wool", color)));
+        }
+
+        registry.register(Block::sprite(DANDELION, "Dandelion"));
+        registry.register(Block::sprite(ROSE, "Rose"));
+        registry.register(Block::sprite(BROWN_MUSHROOM, "Brown mushroom"));
+        registry.register(Block::sprite(RED_MUSHROOM, "Red mushroom"));
+        registry.register(Block::classic(GOLD, "Gold"));
+        registry.register(Block::classic(IRON, "Iron"));
+        registry.register(Block::classic(DOUBLE_SLAB, "Double slab"));
+        registry.register(Block::classic(SLAB, "Slab"));
+        registry.register(Block::classic(BRICK, "Brick"));
+        registry.register(Block::classic(TNT, "TNT"));
+        registry.register(Block::classic(BOOKSHELF, "Bookshelf"));
+        registry.register(Block::classic(MOSSY_COBBLESTONE, "Mossy cobblestone"));
+        registry.register(Block::classic(OBSIDIAN, "Obsidian"));
+
+        registry.register(Block::custom(COBBLESTONE_SLAB, "Cobblestone slab", SLAB));
+        registry.register(Block {
+            solid: false,
+            transmits_light: true,
+            ..Block::custom(ROPE, "Rope", BROWN_MUSHROOM)
+        });
+        registry.register(Block::custom(SANDSTONE, "Sandstone", SAND));
+        registry.register(Block {
+            solid: false,
+            transmits_light: true,
+            ..Block::custom(SNOW, "Snow", AIR)
+        });
+        registry.register(Block {
+            solid: false,
+            transmits_light: true,
+            ..Block::custom(FIRE, "Fire", LAVA)
+        });
+        registry.register(Block::custom(LIGHT_PINK_WOOL, "Light pink wool", PINK_WOOL));
+        registry.register(Block::custom(
+            FOREST_GREEN_WOOL,
+            "Forest green wool",
+            GREEN_WOOL,
+        ));
+        registry.register(Block::custom(BROWN_WOOL, "Brown wool", DIRT));
+        registry.register(Block::custom(DEEP_BLUE_WOOL, "Deep blue wool", BLUE_WOOL));
+        registry.register(Block::custom(TURQUOISE_WOOL, "Turquoise wool", CYAN_WOOL));
+        registry.register(Block {
+            transmits_light: true,
+            ..Block::custom(ICE, "Ice", GLASS)
+        });
+        registry.register(Block::custom(CERAMIC_TILE, "Ceramic tile", IRON));
+        registry.register(Block::custom(MAGMA, "Magma", OBSIDIAN));
+        registry.register(Block::custom(PILLAR, "Pillar", WHITE_WOOL));
+        registry.register(Block::custom(CRATE, "Crate", PLANKS));
+        registry.register(Block::custom(STONE_BRICK, "Stone brick", STONE));
+
+        registry
+    }
+
+    /// Adds or replaces the block with the same id.
+    pub fn register(&mut self, block: Block) {
+        let id = block.id as usize;
+        self.blocks[id] = Some(block);
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Block> {
+        self.blocks[id as usize].as_ref()
+    }
+
+    pub fn is_valid(&self, id: u8) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Block to send to a client, its fallback if the client lacks CustomBlocks.
+    pub fn for_client(&self, id: u8, custom_blocks: bool) -> u8 {
+        match self.get(id) {
+            Some(block) if !custom_blocks => block.fallback,
+            _ => id,
+        }
+    }
+
+    pub fn can_place(&self, id: u8) -> bool {
+        match self.get(id) {
+            Some(block) => block.placeable,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_full_blocks_are_solid() {
+        let blocks = BlockRegistry::new();
+        let solid = |id| blocks.get(id).unwrap().solid;
+        for id in [STONE, GLASS, LEAVES, SLAB, SANDSTONE, ICE] {
+            assert!(solid(id), "{}", id);
+        }
+        for id in [AIR, SAPLING, WATER, STILL_LAVA, ROSE, ROPE, SNOW, FIRE] {
+            assert!(!solid(id), "{}", id);
+        }
+    }
+}
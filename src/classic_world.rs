@@ -0,0 +1,155 @@
+use crate::nbt::Tag;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Who made a map, as in the `CreatedBy` compound.
+#[derive(Clone, Debug, Default)]
+pub struct CreatedBy {
+    pub service: String,             // e.g. ClassiCube, empty if missing
+    pub username: String,            // empty if missing
+    pub extra: HashMap<String, Tag>, // other tags, kept as read
+}
+
+/// What generated a map, as in the `MapGenerator` compound.
+#[derive(Clone, Debug, Default)]
+pub struct MapGenerator {
+    pub software: String,
+    pub generator_name: String,
+    pub extra: HashMap<String, Tag>, // other tags, kept as read
+}
+
+/// Tags of a ClassicWorld (.cw) file besides the size, blocks and spawn position.
+/// Whatever qubiq doesn't understand is kept as read and written back on save.
+#[derive(Clone, Debug, Default)]
+pub struct ClassicWorldMeta {
+    pub name: Option<String>,
+    pub uuid: Option<[u8; 16]>,
+    pub created_by: Option<CreatedBy>,
+    pub map_generator: Option<MapGenerator>,
+    pub time_created: Option<i64>, // unix time in seconds
+    pub last_accessed: Option<i64>,
+    pub last_modified: Option<i64>,
+    pub spawn_yaw: u8,
+    pub spawn_pitch: u8,
+    pub spawn_extra: HashMap<String, Tag>, // tags of `Spawn` besides the position and rotation
+    pub metadata: Option<HashMap<String, Tag>>, // per software data, e.g. CPE env colors and block definitions
+    pub unknown: HashMap<String, Tag>,          // other root tags
+}
+
+impl ClassicWorldMeta {
+    /// Meta of a map generated by qubiq now.
+    pub fn generated(generator_name: &str) -> Self {
+        let created = now();
+        ClassicWorldMeta {
+            uuid: Some(rand::random()),
+            map_generator: Some(MapGenerator {
+                software: String::from("qubiq"),
+                generator_name: generator_name.to_string(),
+                extra: HashMap::new(),
+            }),
+            time_created: Some(created),
+            last_modified: Some(created),
+            ..Default::default()
+        }
+    }
+
+    /// Reads a root tag, tags with an unexpected type are kept as unknown.
+    pub fn read_tag(&mut self, key: &str, tag: Tag) {
+        match (key, tag) {
+            ("Name", Tag::String(name)) => self.name = Some(name),
+            ("UUID", Tag::ByteArray(bytes)) if bytes.len() == 16 => {
+                let mut uuid = [0u8; 16];
+                for (u, b) in uuid.iter_mut().zip(bytes.iter()) {
+                    *u = *b as u8;
+                }
+                self.uuid = Some(uuid);
+            }
+            ("CreatedBy", Tag::Compound(mut tags)) => {
+                self.created_by = Some(CreatedBy {
+                    service: take_string(&mut tags, "Service"),
+                    username: take_string(&mut tags, "Username"),
+                    extra: tags,
+                })
+            }
+            ("MapGenerator", Tag::Compound(mut tags)) => {
+                self.map_generator = Some(MapGenerator {
+                    software: take_string(&mut tags, "Software"),
+                    generator_name: take_string(&mut tags, "MapGeneratorName"),
+                    extra: tags,
+                })
+            }
+            ("TimeCreated", Tag::Long(time)) => self.time_created = Some(time),
+            ("LastAccessed", Tag::Long(time)) => self.last_accessed = Some(time),
+            ("LastModified", Tag::Long(time)) => self.last_modified = Some(time),
+            ("Metadata", Tag::Compound(tags)) => self.metadata = Some(tags),
+            (key, tag) => {
+                self.unknown.insert(key.to_string(), tag);
+            }
+        }
+    }
+
+    /// Root tags to save, the world adds its size, blocks and spawn.
+    pub fn to_tags(&self) -> HashMap<String, Tag> {
+        let mut m = self.unknown.clone();
+        if let Some(ref name) = self.name {
+            m.insert("Name".into(), Tag::String(name.clone()));
+        }
+        if let Some(uuid) = self.uuid {
+            m.insert(
+                "UUID".into(),
+                Tag::ByteArray(uuid.iter().map(|b| *b as i8).collect()),
+            );
+        }
+        if let Some(ref created_by) = self.created_by {
+            let mut cm = created_by.extra.clone();
+            put_string(&mut cm, "Service", &created_by.service);
+            put_string(&mut cm, "Username", &created_by.username);
+            m.insert("CreatedBy".into(), Tag::Compound(cm));
+        }
+        if let Some(ref map_generator) = self.map_generator {
+            let mut gm = map_generator.extra.clone();
+            put_string(&mut gm, "Software", &map_generator.software);
+            put_string(&mut gm, "MapGeneratorName", &map_generator.generator_name);
+            m.insert("MapGenerator".into(), Tag::Compound(gm));
+        }
+        if let Some(time) = self.time_created {
+            m.insert("TimeCreated".into(), Tag::Long(time));
+        }
+        if let Some(time) = self.last_accessed {
+            m.insert("LastAccessed".into(), Tag::Long(time));
+        }
+        if let Some(time) = self.last_modified {
+            m.insert("LastModified".into(), Tag::Long(time));
+        }
+        if let Some(ref metadata) = self.metadata {
+            m.insert("Metadata".into(), Tag::Compound(metadata.clone()));
+        }
+        m
+    }
+}
+
+/// Takes a non-empty string, anything else stays in the tags to be written back as read.
+fn take_string(tags: &mut HashMap<String, Tag>, key: &str) -> String {
+    match tags.remove(key) {
+        Some(Tag::String(s)) if !s.is_empty() => s,
+        Some(tag) => {
+            tags.insert(key.to_string(), tag);
+            String::new()
+        }
+        None => String::new(),
+    }
+}
+
+/// Empty strings stand for missing tags and are left out.
+fn put_string(tags: &mut HashMap<String, Tag>, key: &str, val: &str) {
+    if !val.is_empty() {
+        tags.insert(key.to_string(), Tag::String(val.to_string()));
+    }
+}
+
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
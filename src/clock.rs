@@ -1,7 +1,6 @@
-use std::thread;
 use std::time::{Duration, SystemTime};
 
-/// Keeps track of the time each tick takes and regulates the server ticks per second (TPS).
+/// Keeps track of the time each tick takes and schedules the server ticks per second (TPS).
 pub struct Clock {
     micros_ema: f32,
     full_tick_millis: u128,
@@ -25,18 +24,18 @@ impl Clock {
         self.time = SystemTime::now();
     }
 
-    /// The tick code has finished executing, record the time and sleep if extra time remains
+    /// The tick code has finished executing, record the time it took
     pub fn finish_tick(&mut self) {
-        match self.time.elapsed() {
-            Ok(duration) => {
-                self.micros_ema =
-                    (99_f32 * self.micros_ema + duration.as_micros() as f32) / 100_f32;
+        if let Ok(duration) = self.time.elapsed() {
+            self.micros_ema = (99_f32 * self.micros_ema + duration.as_micros() as f32) / 100_f32;
+        }
+    }
 
-                if duration.as_millis() < self.full_tick_millis {
-                    thread::sleep(self.full_tick - duration);
-                }
-            }
-            Err(_) => thread::sleep(self.full_tick),
+    /// Time left until the next tick is due, the rest of the tick can be spent waiting for events.
+    pub fn until_next_tick(&self) -> Duration {
+        match self.time.elapsed() {
+            Ok(duration) if duration < self.full_tick => self.full_tick - duration,
+            _ => Duration::from_millis(0),
         }
     }
 
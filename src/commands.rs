@@ -0,0 +1,565 @@
+use crate::bans::{self, Ban};
+use crate::console;
+use crate::packets::{self, ServerPacket};
+use crate::player::Player;
+use crate::rank::Rank;
+use crate::server::{Queue, Server};
+use std::net::IpAddr;
+
+/// Who runs a command, the console passes every rank check.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Caller {
+    Player(i8),
+    Console,
+}
+
+/// Runs a command for the caller, errors are shown to the caller.
+pub type Handler = fn(&mut Server, Caller, &[&str]) -> anyhow::Result<()>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub rank: Rank, // minimum rank allowed to run it
+    pub handler: Handler,
+}
+
+/// Registry of every chat command known to the server.
+pub struct Commands {
+    commands: Vec<Command>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        let mut commands = Commands { commands: vec![] };
+        commands.register(Command {
+            name: "help",
+            aliases: &["h", "commands"],
+            usage: "/help [command]",
+            description: "Lists commands or describes one",
+            rank: Rank::Guest,
+            handler: help,
+        });
+        commands.register(Command {
+            name: "list",
+            aliases: &["players", "who"],
+            usage: "/list",
+            description: "Lists online players",
+            rank: Rank::Guest,
+            handler: list,
+        });
+        commands.register(Command {
+            name: "tps",
+            aliases: &[],
+            usage: "/tps",
+            description: "Shows ticks per second and tick times",
+            rank: Rank::Guest,
+            handler: tps,
+        });
+        commands.register(Command {
+            name: "say",
+            aliases: &["broadcast"],
+            usage: "/say <message>",
+            description: "Broadcasts a message to everyone",
+            rank: Rank::Op,
+            handler: say,
+        });
+        commands.register(Command {
+            name: "kick",
+            aliases: &[],
+            usage: "/kick <player> [reason]",
+            description: "Disconnects a player",
+            rank: Rank::Op,
+            handler: kick,
+        });
+        commands.register(Command {
+            name: "ban",
+            aliases: &[],
+            usage: "/ban <player> [duration] [reason]",
+            description: "Bans a name, durations look like 30m, 12h or 7d",
+            rank: Rank::Op,
+            handler: ban,
+        });
+        commands.register(Command {
+            name: "banip",
+            aliases: &["ipban"],
+            usage: "/banip <player|ip|range> [duration] [reason]",
+            description: "Bans an address or the address of an online player",
+            rank: Rank::Op,
+            handler: banip,
+        });
+        commands.register(Command {
+            name: "unban",
+            aliases: &["pardon"],
+            usage: "/unban <player|ip|range>",
+            description: "Lifts a name or address ban",
+            rank: Rank::Op,
+            handler: unban,
+        });
+        commands.register(Command {
+            name: "whitelist",
+            aliases: &["wl"],
+            usage: "/whitelist <add|remove|list> [player]",
+            description: "Manages who may join when the whitelist is on",
+            rank: Rank::Op,
+            handler: whitelist,
+        });
+        commands.register(Command {
+            name: "rank",
+            aliases: &["setrank"],
+            usage: "/rank <player> <guest|builder|op|owner>",
+            description: "Changes the rank of a player, online or not",
+            rank: Rank::Op,
+            handler: rank,
+        });
+        commands.register(Command {
+            name: "goto",
+            aliases: &["g", "world"],
+            usage: "/goto [world]",
+            description: "Moves you to another world or lists them",
+            rank: Rank::Guest,
+            handler: goto,
+        });
+        commands.register(Command {
+            name: "worlds",
+            aliases: &["maps"],
+            usage: "/worlds",
+            description: "Lists worlds with their size and memory use",
+            rank: Rank::Guest,
+            handler: worlds,
+        });
+        commands.register(Command {
+            name: "save",
+            aliases: &[],
+            usage: "/save",
+            description: "Saves every loaded world",
+            rank: Rank::Op,
+            handler: save,
+        });
+        commands.register(Command {
+            name: "stop",
+            aliases: &["shutdown"],
+            usage: "/stop",
+            description: "Saves the worlds if autosave is on and stops the server",
+            rank: Rank::Owner,
+            handler: stop,
+        });
+        commands
+    }
+
+    pub fn register(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+
+    /// Finds a command by its name or one of its aliases, ignoring case.
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|c| {
+            c.name.eq_ignore_ascii_case(name)
+                || c.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter()
+    }
+}
+
+/// Joins words into as few chat lines as possible, a line holds at most 64 characters.
+pub fn wrap_words<'a, I: IntoIterator<Item = &'a str>>(prefix: &str, words: I) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = prefix.to_string();
+    let mut empty = true;
+    for word in words {
+        if !empty && line.len() + word.len() + 2 > 64 {
+            lines.push(line);
+            line = String::from("&f");
+            empty = true;
+        }
+        if !empty {
+            line.push_str(", ");
+        }
+        line.push_str(word);
+        empty = false;
+    }
+    lines.push(line);
+    lines
+}
+
+fn help(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let rank = server.caller_rank(caller);
+
+    if let Some(name) = args.first() {
+        let (usage, description) = match server.commands.find(name) {
+            Some(command) if command.rank <= rank => (command.usage, command.description),
+            _ => return Err(anyhow::anyhow!("Unknown command: {}", name)),
+        };
+        server.message(caller, format!("&eUsage: &f{}", usage));
+        server.message(caller, format!("&e{}", description));
+        return Ok(());
+    }
+
+    let names = server
+        .commands
+        .iter()
+        .filter(|c| c.rank <= rank)
+        .map(|c| c.name)
+        .collect::<Vec<_>>();
+    for line in wrap_words("&eCommands: &f", names) {
+        server.message(caller, line);
+    }
+    Ok(())
+}
+
+fn list(server: &mut Server, caller: Caller, _args: &[&str]) -> anyhow::Result<()> {
+    let names = server
+        .players
+        .iter()
+        .filter(|p| p.authed)
+        .map(|p| p.name.clone())
+        .collect::<Vec<_>>();
+    let prefix = format!(
+        "&ePlayers ({}/{}): &f",
+        names.len(),
+        server.config.server.max_players
+    );
+    for line in wrap_words(&prefix, names.iter().map(String::as_str)) {
+        server.message(caller, line);
+    }
+    Ok(())
+}
+
+fn tps(server: &mut Server, caller: Caller, _args: &[&str]) -> anyhow::Result<()> {
+    let mspt = server.clock.mspt();
+    let msg = format!(
+        "&eTPS: &f{:.2}/{:.2} &eMSPT: &f{:.2}",
+        server.clock.as_tps(mspt),
+        server.clock.max_tps(),
+        mspt
+    );
+    server.message(caller, msg);
+    Ok(())
+}
+
+fn say(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    if args.is_empty() {
+        return Err(anyhow::anyhow!("Usage: /say <message>"));
+    }
+    let msg = format!("&d[{}] &f{}", server.caller_name(caller), args.join(" "));
+    println!("{}", console::strip_colors(&msg));
+    server.queue.push_back(Queue::ChatMessage(msg));
+    Ok(())
+}
+
+fn kick(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return Err(anyhow::anyhow!("Usage: /kick <player> [reason]")),
+    };
+    let reason = if args.len() > 1 {
+        args[1..].join(" ")
+    } else {
+        format!("Kicked by {}", server.caller_name(caller))
+    };
+
+    let rank = match server.find_player(name) {
+        Some(player) => player.rank,
+        None => return Err(anyhow::anyhow!("Player {} is not online", name)),
+    };
+    if !server.outranks(caller, rank) {
+        return Err(anyhow::anyhow!("You can't kick {}", name));
+    }
+    let player = match server.find_player(name) {
+        Some(player) => player,
+        None => return Err(anyhow::anyhow!("Player {} is not online", name)),
+    };
+    let kicked = player.name.clone();
+    drop_player(player, reason);
+
+    server.message(caller, format!("&eKicked {}", kicked));
+    Ok(())
+}
+
+/// Splits `[duration] [reason]` arguments, the reason defaults to a generic one.
+fn ban_args(server: &Server, caller: Caller, args: &[&str]) -> Ban {
+    let (duration, reason) = match args.first().and_then(|arg| bans::parse_duration(arg)) {
+        Some(duration) => (Some(duration), &args[1..]),
+        None => (None, args),
+    };
+    let reason = if reason.is_empty() {
+        String::from("You are banned from this server")
+    } else {
+        reason.join(" ")
+    };
+    Ban::new(reason, server.caller_name(caller), duration)
+}
+
+fn ban(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return Err(anyhow::anyhow!("Usage: /ban <player> [duration] [reason]")),
+    };
+    if !server.outranks(caller, server.access.ranks.rank_of(name)) {
+        return Err(anyhow::anyhow!("You can't ban {}", name));
+    }
+
+    let ban = ban_args(server, caller, &args[1..]);
+    let msg = ban.message();
+    server.access.bans.ban_name(name, ban)?;
+    println!("{} banned {}", server.caller_name(caller), name);
+
+    if let Some(player) = server.find_player(name) {
+        drop_player(player, msg);
+    }
+    server.message(caller, format!("&eBanned {}", name));
+    Ok(())
+}
+
+fn banip(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let target = match args.first() {
+        Some(target) => *target,
+        None => {
+            return Err(anyhow::anyhow!(
+                "Usage: /banip <player|ip|range> [duration] [reason]"
+            ))
+        }
+    };
+
+    // Addresses and ranges are banned as given, names by the address they play from
+    let range = if target.contains('/') || target.parse::<IpAddr>().is_ok() {
+        target.to_string()
+    } else {
+        match server.find_player(target) {
+            Some(player) => player.addr.ip().to_string(),
+            None => return Err(anyhow::anyhow!("Player {} is not online", target)),
+        }
+    };
+
+    // Nobody may lock out a player they don't outrank
+    if let Some(player) = server.players.iter().find(|p| {
+        p.authed && crate::auth::in_range(p.addr.ip(), &range) && !server.outranks(caller, p.rank)
+    }) {
+        return Err(anyhow::anyhow!(
+            "You can't ban the address of {}",
+            player.name
+        ));
+    }
+
+    let ban = ban_args(server, caller, &args[1..]);
+    let msg = ban.message();
+    server.access.bans.ban_ip(&range, ban)?;
+    println!("{} banned address {}", server.caller_name(caller), range);
+
+    for player in server.players.iter_mut() {
+        if crate::auth::in_range(player.addr.ip(), &range) {
+            drop_player(player, msg.clone());
+        }
+    }
+    server.message(caller, format!("&eBanned address {}", range));
+    Ok(())
+}
+
+fn unban(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let target = match args.first() {
+        Some(target) => *target,
+        None => return Err(anyhow::anyhow!("Usage: /unban <player|ip|range>")),
+    };
+    if !server.access.bans.unban(target)? {
+        return Err(anyhow::anyhow!("{} is not banned", target));
+    }
+    println!("{} unbanned {}", server.caller_name(caller), target);
+    server.message(caller, format!("&eUnbanned {}", target));
+    Ok(())
+}
+
+fn whitelist(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let whitelist = &mut server.access.whitelist;
+    let msg = match args {
+        ["add", name] => match whitelist.add(name)? {
+            true => format!("&eAdded {} to the whitelist", name),
+            false => return Err(anyhow::anyhow!("{} is already whitelisted", name)),
+        },
+        ["remove", name] => match whitelist.remove(name)? {
+            true => format!("&eRemoved {} from the whitelist", name),
+            false => return Err(anyhow::anyhow!("{} is not whitelisted", name)),
+        },
+        ["list"] => {
+            let names = whitelist.names().to_vec();
+            let prefix = format!("&eWhitelisted ({}): &f", names.len());
+            for line in wrap_words(&prefix, names.iter().map(String::as_str)) {
+                server.message(caller, line);
+            }
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Usage: /whitelist <add|remove|list> [player]"
+            ))
+        }
+    };
+    println!(
+        "{}: {}",
+        server.caller_name(caller),
+        console::strip_colors(&msg)
+    );
+    server.message(caller, msg);
+    if !server.config.server.whitelist {
+        server.message(caller, "&eThe whitelist is off in config.yaml".to_string());
+    }
+    Ok(())
+}
+
+fn rank(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let (name, rank) = match args {
+        [name, rank] => match Rank::from_name(rank) {
+            Some(rank) => (*name, rank),
+            None => return Err(anyhow::anyhow!("Unknown rank: {}", rank)),
+        },
+        _ => return Err(anyhow::anyhow!("Usage: /rank <player> <rank>")),
+    };
+
+    // Nobody may hand out more than their own rank, or change the rank of those they don't outrank
+    if rank > server.caller_rank(caller)
+        || !server.outranks(caller, server.access.ranks.rank_of(name))
+    {
+        return Err(anyhow::anyhow!("You can't change the rank of {}", name));
+    }
+    server.access.ranks.set_rank(name, rank)?;
+    println!(
+        "{} set the rank of {} to {}",
+        server.caller_name(caller),
+        name,
+        rank.name()
+    );
+
+    if let Some(player) = server.find_player(name) {
+        player.rank = rank;
+        let _ = packets::update_user_type(
+            &mut player.outbound,
+            ServerPacket::UpdateUserType(rank.user_type()),
+        );
+        player.send_message(format!("&eYour rank is now {}", rank.name()));
+    }
+    server.message(caller, format!("&e{} is now {}", name, rank.name()));
+    Ok(())
+}
+
+fn goto(server: &mut Server, caller: Caller, args: &[&str]) -> anyhow::Result<()> {
+    let pid = match caller {
+        Caller::Player(pid) => pid,
+        Caller::Console => return Err(anyhow::anyhow!("Only players can change worlds")),
+    };
+    let name = match args.first() {
+        Some(name) => *name,
+        None => {
+            let names = server.worlds.names().cloned().collect::<Vec<_>>();
+            for line in wrap_words("&eWorlds: &f", names.iter().map(String::as_str)) {
+                server.message(caller, line);
+            }
+            return Ok(());
+        }
+    };
+    let name = match server.worlds.find(name) {
+        Some(name) => name,
+        None => return Err(anyhow::anyhow!("Unknown world: {}", name)),
+    };
+
+    let old = match server.players.iter().find(|p| p.pid == pid) {
+        Some(player) if player.world == name => {
+            return Err(anyhow::anyhow!("You are already in {}", name))
+        }
+        Some(player) => player.world.clone(),
+        None => return Ok(()),
+    };
+    let others = server
+        .players
+        .iter()
+        .filter(|p| p.pid != pid && p.world == old)
+        .map(|p| p.pid)
+        .collect::<Vec<_>>();
+
+    let world = server.worlds.load(&name, &server.blocks)?;
+    if let Some(player) = server.players.iter_mut().find(|p| p.pid == pid) {
+        // Players left behind are removed before the new level replaces the old one
+        for other in others {
+            packets::despawn_player(&mut player.outbound, ServerPacket::DespawnPlayer(other))?;
+        }
+        player.enter_world(world, &server.blocks)?;
+        println!("{} moved from {} to {}", player.name, old, name);
+    }
+    server
+        .queue
+        .push_back(Queue::DespawnPlayer { pid, world: old });
+    server.queue.push_back(Queue::SpawnPlayer(pid));
+    server.message(caller, format!("&eMoved to {}", name));
+    Ok(())
+}
+
+fn worlds(server: &mut Server, caller: Caller, _args: &[&str]) -> anyhow::Result<()> {
+    let mut lines = Vec::new();
+    let mut loaded = 0;
+    let mut memory = 0;
+    for (name, world) in server.worlds.iter() {
+        let players = server
+            .players
+            .iter()
+            .filter(|p| p.authed && p.world == *name)
+            .count();
+        lines.push(match world {
+            Some(world) => {
+                loaded += 1;
+                memory += world.memory_usage();
+                format!(
+                    "&f{} &7- {}x{}x{}, {}, {} players",
+                    name,
+                    world.width,
+                    world.height,
+                    world.length,
+                    format_size(world.memory_usage()),
+                    players
+                )
+            }
+            None => format!("&f{} &7- unloaded", name),
+        });
+    }
+
+    let header = format!(
+        "&eWorlds ({}/{} loaded, {}):",
+        loaded,
+        lines.len(),
+        format_size(memory)
+    );
+    server.message(caller, header);
+    for line in lines {
+        server.message(caller, line);
+    }
+    Ok(())
+}
+
+fn format_size(bytes: usize) -> String {
+    match bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{} KB", b.div_ceil(1024)),
+        b => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+fn save(server: &mut Server, caller: Caller, _args: &[&str]) -> anyhow::Result<()> {
+    server.worlds.save_all()?;
+    let count = server.worlds.iter_mut().count();
+    server.message(caller, format!("&eSaved {} worlds", count));
+    Ok(())
+}
+
+fn stop(server: &mut Server, caller: Caller, _args: &[&str]) -> anyhow::Result<()> {
+    println!("Server stopped by {}", server.caller_name(caller));
+    server.stopping = true;
+    Ok(())
+}
+
+/// Kicks a player with the reason, the connection is dropped on the next poll.
+fn drop_player(player: &mut Player, reason: String) {
+    println!("Kicking player: {} - {}", player.name, reason);
+    let _ = player.disconnect(reason);
+    player.active = false;
+}
@@ -1,32 +1,60 @@
+use crate::block;
 use serde::{Deserialize, Serialize};
 use std::net::{Ipv4Addr, SocketAddrV4};
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Every section and field falls back to its default, so config files of older versions still load.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct Config {
     pub server: ServerCfg,
     pub simulation: SimulationCfg,
     pub world: WorldCfg,
+    pub heartbeat: HeartbeatCfg,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct ServerCfg {
     pub ip: SocketAddrV4,
     pub name: String,
     pub motd: String,
     pub max_players: i8,
+    pub outbound_high_water: usize, // queued bytes before a player is kicked as too slow
+    pub verify_names: bool,         // only checked while the heartbeat is enabled
+    pub verify_bypass: Vec<String>, // IPs or CIDR ranges allowed to join without verification
+    pub whitelist: bool,            // only names in whitelist.txt may join
+    pub whitelist_message: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct SimulationCfg {
-    pub server_tick_rate: u64,
-    pub sand_tick_rate: u64,
+    pub server_tick_rate: u64, // milliseconds per server tick
+    pub sand_tick_rate: u64,   // milliseconds for sand and gravel to fall one block
+    pub water_tick_rate: u64,  // milliseconds for flowing water to spread one block
+    pub lava_tick_rate: u64,
+    pub finite_liquids: bool, // liquids move instead of spreading endlessly
+    pub sponge_radius: i16,
+    pub random_tick_rate: u64, // milliseconds between random ticks growing grass and trees
+    pub random_tick_speed: u32, // blocks picked in each 16x16x16 blocks every random tick
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct WorldCfg {
     pub gen: WorldGenCfg,
     pub path: String,
     pub autosave: bool,
+    pub unload_after: u64, // seconds a world without players stays loaded, 0 keeps them all
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HeartbeatCfg {
+    pub enabled: bool,
+    pub url: String,
+    pub public: bool,
+    pub interval: u64, // seconds between heartbeats
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -36,35 +64,118 @@ pub enum WorldGenCfg {
         width: i16,
         height: i16,
         length: i16,
+        #[serde(default = "default_layers")]
+        layers: Vec<FlatLayer>, // from the bottom up
+        #[serde(default)]
+        sea_level: i16, // air below it is filled with water
+        #[serde(default)]
+        bedrock: bool, // a floor of bedrock under the layers
+    },
+    Generated {
+        generator: GeneratorKind,
+        width: i16,
+        height: i16,
+        length: i16,
+        #[serde(default)]
+        seed: Option<i64>, // picked at random if empty
     },
 }
 
-impl Default for Config {
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FlatLayer {
+    pub block: u8,
+    pub thickness: i16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum GeneratorKind {
+    Hills,
+    Islands,
+    Desert,
+    Classic,
+}
+
+impl Default for ServerCfg {
     fn default() -> Self {
-        Config {
-            server: ServerCfg {
-                ip: SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 25565),
-                name: "Qubiq Server!".to_string(),
-                motd: "Welcome to server!".to_string(),
-                max_players: 10,
-            },
-            simulation: SimulationCfg {
-                server_tick_rate: 50,
-                sand_tick_rate: 20,
-            },
-            world: WorldCfg {
-                gen: WorldGenCfg::FlatMap {
-                    width: 64,
-                    height: 32,
-                    length: 64,
-                },
-                path: "maps/test.cw".to_string(),
-                autosave: true,
+        ServerCfg {
+            ip: SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 25565),
+            name: "Qubiq Server!".to_string(),
+            motd: "Welcome to server!".to_string(),
+            max_players: 10,
+            outbound_high_water: 16 * 1024 * 1024,
+            verify_names: true,
+            verify_bypass: vec![
+                "127.0.0.0/8".to_string(),
+                "::1".to_string(),
+                "10.0.0.0/8".to_string(),
+                "172.16.0.0/12".to_string(),
+                "192.168.0.0/16".to_string(),
+            ],
+            whitelist: false,
+            whitelist_message: "You are not whitelisted on this server!".to_string(),
+        }
+    }
+}
+
+impl Default for SimulationCfg {
+    fn default() -> Self {
+        SimulationCfg {
+            server_tick_rate: 50,
+            sand_tick_rate: 20,
+            water_tick_rate: 250,
+            lava_tick_rate: 1500,
+            finite_liquids: false,
+            sponge_radius: 2,
+            random_tick_rate: 50,
+            random_tick_speed: 3,
+        }
+    }
+}
+
+impl Default for WorldCfg {
+    fn default() -> Self {
+        WorldCfg {
+            gen: WorldGenCfg::FlatMap {
+                width: 64,
+                height: 32,
+                length: 64,
+                layers: default_layers(),
+                sea_level: 0,
+                bedrock: false,
             },
+            path: "maps/test.cw".to_string(),
+            autosave: true,
+            unload_after: 300,
+        }
+    }
+}
+
+impl Default for HeartbeatCfg {
+    fn default() -> Self {
+        HeartbeatCfg {
+            enabled: false,
+            url: "https://www.classicube.net/server/heartbeat".to_string(),
+            public: true,
+            interval: 45,
         }
     }
 }
 
+/// Dirt with grass on top.
+fn default_layers() -> Vec<FlatLayer> {
+    vec![
+        FlatLayer {
+            block: block::DIRT,
+            thickness: 15,
+        },
+        FlatLayer {
+            block: block::GRASS,
+            thickness: 1,
+        },
+    ]
+}
+
 impl Config {
     pub fn new() -> anyhow::Result<Self> {
         let mut config = Config::default();
@@ -77,7 +188,7 @@ impl Config {
                     let wr_str = serde_yaml::to_string(&config)?;
                     std::fs::write("config.yaml", wr_str)?;
                 } else {
-                    panic!(e);
+                    panic!("{}", e);
                 }
             }
         }
@@ -85,3 +196,59 @@ impl Config {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_config_of_older_versions() {
+        // config.yaml as written by the first release
+        let yaml = r#"---
+server:
+  ip: "127.0.0.1:25565"
+  name: Qubiq Server!
+  motd: Welcome to server!
+  max_players: 10
+simulation:
+  server_tick_rate: 50
+  sand_tick_rate: 20
+world:
+  gen:
+    FlatMap:
+      width: 64
+      height: 32
+      length: 64
+  path: maps/test.cw
+  autosave: true
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.server.max_players, 10);
+        assert_eq!(config.server.outbound_high_water, 16 * 1024 * 1024);
+        assert_eq!(config.simulation.water_tick_rate, 250);
+        assert_eq!(config.world.unload_after, 300);
+        assert!(!config.heartbeat.enabled);
+        match config.world.gen {
+            WorldGenCfg::FlatMap {
+                width,
+                ref layers,
+                sea_level,
+                bedrock,
+                ..
+            } => {
+                assert_eq!(width, 64);
+                assert_eq!(layers.len(), 2);
+                assert_eq!(sea_level, 0);
+                assert!(!bedrock);
+            }
+            _ => panic!("expected a flat map"),
+        }
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let yaml = serde_yaml::to_string(&Config::default()).unwrap();
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(config.server.name, Config::default().server.name);
+    }
+}
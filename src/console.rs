@@ -0,0 +1,41 @@
+use mio::Waker;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+/// Reads admin commands from stdin on a separate thread, the lines are picked up by the server
+/// which gets woken up for each of them.
+pub fn start(waker: Arc<Waker>) -> anyhow::Result<Receiver<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("console".to_string())
+        .spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+                let _ = waker.wake();
+            }
+        })?;
+    Ok(receiver)
+}
+
+/// Removes the &-color codes of a chat message to print it in the console.
+pub fn strip_colors(msg: &str) -> String {
+    let mut res = String::with_capacity(msg.len());
+    let mut chars = msg.chars();
+    while let Some(c) = chars.next() {
+        if c == '&' {
+            chars.next();
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
@@ -0,0 +1,646 @@
+use crate::block;
+use crate::config::{FlatLayer, GeneratorKind};
+use crate::noise::{CombinedNoise, JavaRandom, OctaveNoise};
+use crate::World;
+use std::f32::consts::PI;
+
+/// Fills a freshly created world, which is all air, with terrain.
+pub trait WorldGenerator {
+    fn generate(&self, world: &mut World);
+
+    /// Saved in the map as the name of its generator.
+    fn name(&self) -> &'static str;
+}
+
+/// Builds the generator picked in the config.
+pub fn from_kind(kind: GeneratorKind, seed: i64) -> Box<dyn WorldGenerator> {
+    match kind {
+        GeneratorKind::Hills => Box::new(HillsGenerator { seed }),
+        GeneratorKind::Islands => Box::new(IslandsGenerator { seed }),
+        GeneratorKind::Desert => Box::new(DesertGenerator { seed }),
+        GeneratorKind::Classic => Box::new(ClassicGenerator { seed }),
+    }
+}
+
+/// Flat layers of blocks stacked from the bottom up, like superflat maps.
+pub struct FlatGenerator {
+    pub layers: Vec<FlatLayer>,
+    pub sea_level: i16,
+    pub bedrock: bool,
+}
+
+impl WorldGenerator for FlatGenerator {
+    fn name(&self) -> &'static str {
+        "flat"
+    }
+
+    fn generate(&self, world: &mut World) {
+        let mut column = Vec::new();
+        if self.bedrock {
+            column.push(block::BEDROCK);
+        }
+        for layer in self.layers.iter() {
+            for _ in 0..layer.thickness {
+                column.push(layer.block);
+            }
+        }
+        while column.len() < self.sea_level.clamp(0, world.height) as usize {
+            column.push(block::STILL_WATER);
+        }
+        column.truncate(world.height as usize);
+
+        for z in 0..world.length as i32 {
+            for x in 0..world.width as i32 {
+                for (y, block_type) in column.iter().enumerate() {
+                    put(world, x, y as i32, z, *block_type);
+                }
+            }
+        }
+    }
+}
+
+/// Rolling grassy hills with scattered trees.
+pub struct HillsGenerator {
+    pub seed: i64,
+}
+
+impl WorldGenerator for HillsGenerator {
+    fn name(&self) -> &'static str {
+        "hills"
+    }
+
+    fn generate(&self, world: &mut World) {
+        let mut rnd = JavaRandom::new(self.seed);
+        let noise =
+            CombinedNoise::new(OctaveNoise::new(&mut rnd, 8), OctaveNoise::new(&mut rnd, 8));
+        let heights = heightmap(world, |x, z| {
+            world.height as f64 / 2.0 + noise.compute(x * 0.8, z * 0.8) / 4.0
+        });
+
+        for z in 0..world.length as i32 {
+            for x in 0..world.width as i32 {
+                let top = heights[(z * world.width as i32 + x) as usize];
+                fill_column(world, x, z, top, block::GRASS, block::DIRT, 3);
+            }
+        }
+        plant_trees(world, &mut rnd, &heights);
+    }
+}
+
+/// An ocean at half of the height with sandy islands rising out of it.
+pub struct IslandsGenerator {
+    pub seed: i64,
+}
+
+impl WorldGenerator for IslandsGenerator {
+    fn name(&self) -> &'static str {
+        "islands"
+    }
+
+    fn generate(&self, world: &mut World) {
+        let mut rnd = JavaRandom::new(self.seed);
+        let noise =
+            CombinedNoise::new(OctaveNoise::new(&mut rnd, 8), OctaveNoise::new(&mut rnd, 8));
+        let water_level = world.height as i32 / 2;
+        let heights = heightmap(world, |x, z| {
+            (water_level - 6) as f64 + noise.compute(x * 0.6, z * 0.6) / 5.0
+        });
+
+        for z in 0..world.length as i32 {
+            for x in 0..world.width as i32 {
+                let top = heights[(z * world.width as i32 + x) as usize];
+                if top > water_level + 1 {
+                    fill_column(world, x, z, top, block::GRASS, block::DIRT, 3);
+                } else {
+                    fill_column(world, x, z, top, block::SAND, block::SAND, 3);
+                }
+                for y in top + 1..=water_level {
+                    put(world, x, y, z, block::STILL_WATER);
+                }
+            }
+        }
+        plant_trees(world, &mut rnd, &heights);
+    }
+}
+
+/// Low sand dunes over stone.
+pub struct DesertGenerator {
+    pub seed: i64,
+}
+
+impl WorldGenerator for DesertGenerator {
+    fn name(&self) -> &'static str {
+        "desert"
+    }
+
+    fn generate(&self, world: &mut World) {
+        let mut rnd = JavaRandom::new(self.seed);
+        let noise =
+            CombinedNoise::new(OctaveNoise::new(&mut rnd, 8), OctaveNoise::new(&mut rnd, 8));
+        let heights = heightmap(world, |x, z| {
+            world.height as f64 / 2.0 + noise.compute(x * 1.3, z * 1.3) / 10.0
+        });
+
+        for z in 0..world.length as i32 {
+            for x in 0..world.width as i32 {
+                let top = heights[(z * world.width as i32 + x) as usize];
+                fill_column(world, x, z, top, block::SAND, block::SAND, 5);
+            }
+        }
+    }
+}
+
+/// Port of the terrain generator of classic 0.30, the same seed gives the same map.
+pub struct ClassicGenerator {
+    pub seed: i64,
+}
+
+impl WorldGenerator for ClassicGenerator {
+    fn name(&self) -> &'static str {
+        "classic"
+    }
+
+    fn generate(&self, world: &mut World) {
+        let mut rnd = JavaRandom::new(self.seed);
+        let water_level = world.height as i32 / 2;
+        let heights = classic_heightmap(world, &mut rnd, water_level);
+
+        classic_strata(world, &mut rnd, &heights);
+        carve_caves(world, &mut rnd);
+        carve_ore_veins(world, &mut rnd, block::COAL_ORE, 0.9);
+        carve_ore_veins(world, &mut rnd, block::IRON_ORE, 0.7);
+        carve_ore_veins(world, &mut rnd, block::GOLD_ORE, 0.5);
+        flood_water(world, &mut rnd, water_level);
+        flood_lava(world, &mut rnd, water_level);
+        classic_surface(world, &mut rnd, &heights, water_level);
+        plant_flowers(world, &mut rnd, &heights);
+        plant_mushrooms(world, &mut rnd, &heights);
+        plant_trees(world, &mut rnd, &heights);
+    }
+}
+
+fn classic_heightmap(world: &World, rnd: &mut JavaRandom, water_level: i32) -> Vec<i32> {
+    let noise1 = CombinedNoise::new(OctaveNoise::new(rnd, 8), OctaveNoise::new(rnd, 8));
+    let noise2 = CombinedNoise::new(OctaveNoise::new(rnd, 8), OctaveNoise::new(rnd, 8));
+    let noise3 = OctaveNoise::new(rnd, 6);
+
+    let mut heights = Vec::with_capacity(world.width as usize * world.length as usize);
+    for z in 0..world.length as i32 {
+        for x in 0..world.width as i32 {
+            let (x, z) = (x as f64, z as f64);
+            let low = noise1.compute(x * 1.3, z * 1.3) / 6.0 - 4.0;
+            let mut height = low;
+            if noise3.compute(x, z) <= 0.0 {
+                let high = noise2.compute(x * 1.3, z * 1.3) / 5.0 + 6.0;
+                height = low.max(high);
+            }
+            height *= 0.5;
+            if height < 0.0 {
+                height *= 0.8;
+            }
+            heights.push((height + water_level as f64) as i32);
+        }
+    }
+    heights
+}
+
+/// Still lava at the bottom, then stone and a layer of dirt of varying thickness.
+fn classic_strata(world: &mut World, rnd: &mut JavaRandom, heights: &[i32]) {
+    let noise = OctaveNoise::new(rnd, 8);
+    let max_y = world.height as i32 - 1;
+
+    for z in 0..world.length as i32 {
+        for x in 0..world.width as i32 {
+            let dirt_thickness = (noise.compute(x as f64, z as f64) / 24.0) as i32 - 4;
+            let dirt_height = heights[(z * world.width as i32 + x) as usize];
+            let stone_height = (dirt_height + dirt_thickness).min(max_y);
+            let dirt_height = dirt_height.min(max_y);
+
+            put(world, x, 0, z, block::STILL_LAVA);
+            for y in 1..=stone_height {
+                put(world, x, y, z, block::STONE);
+            }
+            for y in (stone_height + 1).max(1)..=dirt_height {
+                put(world, x, y, z, block::DIRT);
+            }
+        }
+    }
+}
+
+fn carve_caves(world: &mut World, rnd: &mut JavaRandom) {
+    let (width, height, length) = dimensions(world);
+    let count = width * height * length / 8192;
+
+    for _ in 0..count {
+        let mut cave_x = rnd.next_int(width) as f32;
+        let mut cave_y = rnd.next_int(height) as f32;
+        let mut cave_z = rnd.next_int(length) as f32;
+        let cave_len = (rnd.next_float() * rnd.next_float() * 200.0) as i32;
+
+        let mut theta = rnd.next_float() * PI * 2.0;
+        let mut delta_theta = 0.0;
+        let mut phi = rnd.next_float() * PI * 2.0;
+        let mut delta_phi = 0.0;
+        let cave_radius = rnd.next_float() * rnd.next_float();
+
+        for i in 0..cave_len {
+            cave_x += theta.sin() * phi.cos();
+            cave_z += theta.cos() * phi.cos();
+            cave_y += phi.sin();
+
+            theta += delta_theta * 0.2;
+            delta_theta = delta_theta * 0.9 + rnd.next_float() - rnd.next_float();
+            phi = phi / 2.0 + delta_phi / 4.0;
+            delta_phi = delta_phi * 0.75 + rnd.next_float() - rnd.next_float();
+            if rnd.next_float() < 0.25 {
+                continue;
+            }
+
+            let center_x = (cave_x + (rnd.next_int(4) - 2) as f32 * 0.2) as i32;
+            let center_y = (cave_y + (rnd.next_int(4) - 2) as f32 * 0.2) as i32;
+            let center_z = (cave_z + (rnd.next_int(4) - 2) as f32 * 0.2) as i32;
+
+            let mut radius = (height - center_y) as f32 / height as f32;
+            radius = 1.2 + (radius * 3.5 + 1.0) * cave_radius;
+            radius *= (i as f32 * PI / cave_len as f32).sin();
+            fill_oblate_spheroid(world, (center_x, center_y, center_z), radius, block::AIR);
+        }
+    }
+}
+
+fn carve_ore_veins(world: &mut World, rnd: &mut JavaRandom, ore: u8, abundance: f32) {
+    let (width, height, length) = dimensions(world);
+    let count = (width * height * length) as f32 * abundance / 16384.0;
+
+    for _ in 0..count as i32 {
+        let mut vein_x = rnd.next_int(width) as f32;
+        let mut vein_y = rnd.next_int(height) as f32;
+        let mut vein_z = rnd.next_int(length) as f32;
+        let vein_len = rnd.next_float() * rnd.next_float() * 75.0 * abundance;
+
+        let mut theta = rnd.next_float() * PI * 2.0;
+        let mut delta_theta = 0.0;
+        let mut phi = rnd.next_float() * PI * 2.0;
+        let mut delta_phi = 0.0;
+
+        for i in 0..vein_len as i32 {
+            vein_x += theta.sin() * phi.cos();
+            vein_z += theta.cos() * phi.cos();
+            vein_y += phi.sin();
+
+            theta = delta_theta * 0.2;
+            delta_theta = delta_theta * 0.9 + rnd.next_float() - rnd.next_float();
+            phi = phi / 2.0 + delta_phi / 4.0;
+            delta_phi = delta_phi * 0.9 + rnd.next_float() - rnd.next_float();
+
+            let radius = abundance * (i as f32 * PI / vein_len).sin() + 1.0;
+            let center = (vein_x as i32, vein_y as i32, vein_z as i32);
+            fill_oblate_spheroid(world, center, radius, ore);
+        }
+    }
+}
+
+/// Replaces stone in a spheroid squashed to half of its height.
+fn fill_oblate_spheroid(world: &mut World, center: (i32, i32, i32), radius: f32, block_type: u8) {
+    let (cx, cy, cz) = center;
+    let (width, height, length) = dimensions(world);
+    let start = |c: i32| (c as f32 - radius).max(0.0).floor() as i32;
+    let end = |c: i32, size: i32| (c as f32 + radius).min((size - 1) as f32).floor() as i32;
+    let radius_sq = radius * radius;
+
+    for y in start(cy)..=end(cy, height) {
+        let dy = (y - cy) as f32;
+        for z in start(cz)..=end(cz, length) {
+            let dz = (z - cz) as f32;
+            for x in start(cx)..=end(cx, width) {
+                let dx = (x - cx) as f32;
+                if dx * dx + 2.0 * dy * dy + dz * dz < radius_sq
+                    && get(world, x, y, z) == Some(block::STONE)
+                {
+                    put(world, x, y, z, block_type);
+                }
+            }
+        }
+    }
+}
+
+/// Fills the borders of the map below the water level with water, then adds lakes.
+fn flood_water(world: &mut World, rnd: &mut JavaRandom, water_level: i32) {
+    let (width, _, length) = dimensions(world);
+    let water_y = water_level - 1;
+
+    for x in 0..width {
+        flood_fill(world, (x, water_y, 0), block::STILL_WATER);
+        flood_fill(world, (x, water_y, length - 1), block::STILL_WATER);
+    }
+    for z in 0..length {
+        flood_fill(world, (0, water_y, z), block::STILL_WATER);
+        flood_fill(world, (width - 1, water_y, z), block::STILL_WATER);
+    }
+
+    for _ in 0..width * length / 800 {
+        let x = rnd.next_int(width);
+        let z = rnd.next_int(length);
+        let y = water_level - rnd.range(1, 3);
+        flood_fill(world, (x, y, z), block::STILL_WATER);
+    }
+}
+
+fn flood_lava(world: &mut World, rnd: &mut JavaRandom, water_level: i32) {
+    let (width, height, length) = dimensions(world);
+
+    for _ in 0..width * height * length / 20000 {
+        let x = rnd.next_int(width);
+        let z = rnd.next_int(length);
+        let y = ((water_level - 3) as f32 * rnd.next_float() * rnd.next_float()) as i32;
+        flood_fill(world, (x, y, z), block::STILL_LAVA);
+    }
+}
+
+/// Fills the air connected to `start` sideways and downwards.
+fn flood_fill(world: &mut World, start: (i32, i32, i32), block_type: u8) {
+    let mut stack = vec![start];
+    while let Some((x, y, z)) = stack.pop() {
+        if get(world, x, y, z) != Some(block::AIR) {
+            continue;
+        }
+        put(world, x, y, z, block_type);
+        stack.push((x + 1, y, z));
+        stack.push((x - 1, y, z));
+        stack.push((x, y, z + 1));
+        stack.push((x, y, z - 1));
+        stack.push((x, y - 1, z));
+    }
+}
+
+/// Grass on top, sand on beaches and gravel under some of the water.
+fn classic_surface(world: &mut World, rnd: &mut JavaRandom, heights: &[i32], water_level: i32) {
+    let noise1 = OctaveNoise::new(rnd, 8);
+    let noise2 = OctaveNoise::new(rnd, 8);
+
+    for z in 0..world.length as i32 {
+        for x in 0..world.width as i32 {
+            let y = heights[(z * world.width as i32 + x) as usize];
+            if y < 0 || y >= world.height as i32 {
+                continue;
+            }
+            let above = get(world, x, y + 1, z).unwrap_or(block::AIR);
+            let (nx, nz) = (x as f64, z as f64);
+            if above == block::STILL_WATER && noise2.compute(nx, nz) > 12.0 {
+                put(world, x, y, z, block::GRAVEL);
+            } else if above == block::AIR {
+                if y <= water_level && noise1.compute(nx, nz) > 8.0 {
+                    put(world, x, y, z, block::SAND);
+                } else {
+                    put(world, x, y, z, block::GRASS);
+                }
+            }
+        }
+    }
+}
+
+fn plant_flowers(world: &mut World, rnd: &mut JavaRandom, heights: &[i32]) {
+    let (width, height, length) = dimensions(world);
+
+    for _ in 0..width * length / 3000 {
+        let flower = if rnd.next_int(2) == 0 {
+            block::DANDELION
+        } else {
+            block::ROSE
+        };
+        let patch_x = rnd.next_int(width);
+        let patch_z = rnd.next_int(length);
+        for _ in 0..10 {
+            let (mut x, mut z) = (patch_x, patch_z);
+            for _ in 0..5 {
+                x += rnd.next_int(6) - rnd.next_int(6);
+                z += rnd.next_int(6) - rnd.next_int(6);
+                if x < 0 || z < 0 || x >= width || z >= length {
+                    continue;
+                }
+                let y = heights[(z * width + x) as usize] + 1;
+                if y <= 0 || y >= height {
+                    continue;
+                }
+                if get(world, x, y, z) == Some(block::AIR)
+                    && get(world, x, y - 1, z) == Some(block::GRASS)
+                {
+                    put(world, x, y, z, flower);
+                }
+            }
+        }
+    }
+}
+
+fn plant_mushrooms(world: &mut World, rnd: &mut JavaRandom, heights: &[i32]) {
+    let (width, height, length) = dimensions(world);
+
+    for _ in 0..width * height * length / 2000 {
+        let mushroom = if rnd.next_int(2) == 0 {
+            block::BROWN_MUSHROOM
+        } else {
+            block::RED_MUSHROOM
+        };
+        let patch_x = rnd.next_int(width);
+        let patch_y = rnd.next_int(height);
+        let patch_z = rnd.next_int(length);
+        for _ in 0..20 {
+            let (mut x, mut y, mut z) = (patch_x, patch_y, patch_z);
+            for _ in 0..5 {
+                x += rnd.next_int(6) - rnd.next_int(6);
+                y += rnd.next_int(2) - rnd.next_int(2);
+                z += rnd.next_int(6) - rnd.next_int(6);
+                if x < 0 || z < 0 || x >= width || z >= length || y < 1 || y >= height {
+                    continue;
+                }
+                // Only in caves, below the surface
+                if y >= heights[(z * width + x) as usize] - 1 {
+                    continue;
+                }
+                if get(world, x, y, z) == Some(block::AIR)
+                    && get(world, x, y - 1, z) == Some(block::STONE)
+                {
+                    put(world, x, y, z, mushroom);
+                }
+            }
+        }
+    }
+}
+
+/// Patches of trees growing on grass.
+fn plant_trees(world: &mut World, rnd: &mut JavaRandom, heights: &[i32]) {
+    let (width, height, length) = dimensions(world);
+
+    for _ in 0..width * length / 4000 {
+        let patch_x = rnd.next_int(width);
+        let patch_z = rnd.next_int(length);
+        for _ in 0..20 {
+            let (mut x, mut z) = (patch_x, patch_z);
+            for _ in 0..20 {
+                x += rnd.next_int(6) - rnd.next_int(6);
+                z += rnd.next_int(6) - rnd.next_int(6);
+                if x < 0 || z < 0 || x >= width || z >= length || rnd.next_float() >= 0.25 {
+                    continue;
+                }
+                let y = heights[(z * width + x) as usize] + 1;
+                if y >= height {
+                    continue;
+                }
+                let tree_height = 5 + rnd.next_int(3);
+                if get(world, x, y - 1, z) == Some(block::GRASS)
+                    && can_grow_tree(world, (x, y, z), tree_height)
+                {
+                    grow_tree(world, rnd, (x, y, z), tree_height);
+                }
+            }
+        }
+    }
+}
+
+fn can_grow_tree(world: &World, base: (i32, i32, i32), tree_height: i32) -> bool {
+    let (x, y, z) = base;
+    let air = |tx, ty, tz| get(world, tx, ty, tz) == Some(block::AIR);
+
+    // Room for the trunk, then for the leaves
+    for ty in y..y + tree_height - 4 {
+        for tz in z - 1..=z + 1 {
+            for tx in x - 1..=x + 1 {
+                if !air(tx, ty, tz) {
+                    return false;
+                }
+            }
+        }
+    }
+    for ty in y + tree_height - 4..y + tree_height {
+        for tz in z - 2..=z + 2 {
+            for tx in x - 2..=x + 2 {
+                if !air(tx, ty, tz) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+fn grow_tree(world: &mut World, rnd: &mut JavaRandom, base: (i32, i32, i32), tree_height: i32) {
+    let (x, y, z) = base;
+    let top_start = y + tree_height - 2;
+
+    // Two wide layers of leaves with random corners, then two narrow ones shaped like a plus
+    for ty in y + tree_height - 4..top_start {
+        for dz in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let corner = dx.abs() == 2 && dz.abs() == 2;
+                if !corner || rnd.next_float() >= 0.5 {
+                    put(world, x + dx, ty, z + dz, block::LEAVES);
+                }
+            }
+        }
+    }
+    for ty in top_start..y + tree_height {
+        for dz in -1i32..=1 {
+            for dx in -1i32..=1 {
+                if dx == 0 || dz == 0 || (ty == top_start && rnd.next_float() >= 0.5) {
+                    put(world, x + dx, ty, z + dz, block::LEAVES);
+                }
+            }
+        }
+    }
+    for ty in y..y + tree_height - 1 {
+        put(world, x, ty, z, block::LOG);
+    }
+}
+
+/// Surface height of every column, indexed by `z * width + x` and kept inside of the map.
+fn heightmap(world: &World, height_at: impl Fn(f64, f64) -> f64) -> Vec<i32> {
+    let max_y = world.height as i32 - 2;
+    let mut heights = Vec::with_capacity(world.width as usize * world.length as usize);
+    for z in 0..world.length as i32 {
+        for x in 0..world.width as i32 {
+            let height = height_at(x as f64, z as f64) as i32;
+            heights.push(height.clamp(1, max_y.max(1)));
+        }
+    }
+    heights
+}
+
+/// Fills a column up to `top` with `surface`, `depth` blocks of `filler` below it and stone under that.
+fn fill_column(world: &mut World, x: i32, z: i32, top: i32, surface: u8, filler: u8, depth: i32) {
+    for y in 0..top {
+        let block_type = if y < top - depth {
+            block::STONE
+        } else {
+            filler
+        };
+        put(world, x, y, z, block_type);
+    }
+    put(world, x, top, z, surface);
+}
+
+fn dimensions(world: &World) -> (i32, i32, i32) {
+    (world.width as i32, world.height as i32, world.length as i32)
+}
+
+fn get(world: &World, x: i32, y: i32, z: i32) -> Option<u8> {
+    world.get_block(x as i16, y as i16, z as i16)
+}
+
+/// Sets a block, ignoring positions outside of the map.
+fn put(world: &mut World, x: i32, y: i32, z: i32, block_type: u8) {
+    let _ = world.set_block(x as i16, y as i16, z as i16, block_type);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classic(seed: i64) -> World {
+        let mut world = World::new(64, 64, 64);
+        ClassicGenerator { seed }.generate(&mut world);
+        world
+    }
+
+    #[test]
+    fn flat_map_ignores_sea_level_outside_of_the_map() {
+        for sea_level in [-1, i16::MAX] {
+            let mut world = World::new(16, 16, 16);
+            let layers = vec![FlatLayer {
+                block: block::DIRT,
+                thickness: 2,
+            }];
+            FlatGenerator {
+                layers,
+                sea_level,
+                bedrock: false,
+            }
+            .generate(&mut world);
+            assert_eq!(world.get_block(0, 1, 0), Some(block::DIRT));
+            let water = if sea_level < 0 {
+                block::AIR
+            } else {
+                block::STILL_WATER
+            };
+            assert_eq!(world.get_block(0, 15, 0), Some(water));
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_map() {
+        assert_eq!(classic(1234).blocks, classic(1234).blocks);
+        assert_ne!(classic(1234).blocks, classic(4321).blocks);
+    }
+
+    #[test]
+    fn classic_heightmap_is_stable() {
+        let world = World::new(64, 64, 64);
+        let mut rnd = JavaRandom::new(1234);
+        let heights = classic_heightmap(&world, &mut rnd, 32);
+        let sum = heights
+            .iter()
+            .fold(0u64, |h, &y| h.wrapping_mul(31).wrapping_add(y as u64));
+        // pinned so any change to the noise or the random sequence shows up
+        assert_eq!(heights[..8], [35, 34, 34, 33, 33, 33, 33, 33]);
+        assert_eq!(sum, 6408236844030321555);
+    }
+}
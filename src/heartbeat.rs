@@ -0,0 +1,183 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::packets;
+
+/// Announces the server to a server list (classicube.net format) from a background thread.
+pub struct Heartbeat {
+    url: String,
+    name: String,
+    port: u16,
+    max_players: i8,
+    public: bool,
+    salt: String,
+    interval: Duration,
+
+    // updated by the server whenever players join or leave
+    users: Arc<AtomicUsize>,
+}
+
+impl Heartbeat {
+    pub fn new(config: &Config, salt: String, users: Arc<AtomicUsize>) -> Self {
+        Heartbeat {
+            url: config.heartbeat.url.clone(),
+            name: config.server.name.clone(),
+            port: config.server.ip.port(),
+            max_players: config.server.max_players,
+            public: config.heartbeat.public,
+            salt,
+            interval: Duration::from_secs(config.heartbeat.interval),
+            users,
+        }
+    }
+
+    /// Keeps sending heartbeats until the process exits.
+    pub fn start(self) -> anyhow::Result<()> {
+        thread::Builder::new()
+            .name("heartbeat".to_string())
+            .spawn(move || {
+                let agent = ureq::AgentBuilder::new()
+                    .timeout(Duration::from_secs(10))
+                    .build();
+                let mut play_url = String::new();
+                loop {
+                    match self.send(&agent) {
+                        Ok(url) => {
+                            // The list answers with the same url every time, only log changes
+                            if url != play_url {
+                                println!("Heartbeat: play at {}", url);
+                                play_url = url;
+                            }
+                        }
+                        Err(e) => println!("Heartbeat failed: {}", e),
+                    }
+                    thread::sleep(self.interval);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn send(&self, agent: &ureq::Agent) -> anyhow::Result<String> {
+        let users = self.users.load(Ordering::Relaxed);
+        let software = format!("{} {}", packets::SOFTWARE_NAME, env!("CARGO_PKG_VERSION"));
+
+        let response = agent
+            .get(&self.url)
+            .query("name", &self.name)
+            .query("port", &self.port.to_string())
+            .query("users", &users.to_string())
+            .query("max", &self.max_players.to_string())
+            .query("salt", &self.salt)
+            .query("public", if self.public { "True" } else { "False" })
+            .query("software", &software)
+            .query("version", &packets::PROTOCOL_VERSION.to_string())
+            .call();
+
+        let body = match response {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(code, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                return Err(anyhow::anyhow!("status {} - {}", code, body.trim()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Errors are reported as a json object instead of the play url
+        let body = body.trim();
+        if body.starts_with("http") {
+            Ok(body.to_string())
+        } else {
+            Err(anyhow::anyhow!("{}", body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    fn decode(s: &str) -> String {
+        let mut out = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'+' => out.push(b' '),
+                b'%' => {
+                    let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                    out.push(u8::from_str_radix(&hex, 16).unwrap());
+                }
+                b => out.push(b),
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn sends_server_details_to_the_list() {
+        // Stand-in for the server list answering with the play url
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let list = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+            let body = "http://list.test/play/abc";
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request_line
+        });
+
+        let heartbeat = Heartbeat {
+            url: format!("http://{}/server/heartbeat", addr),
+            name: "Test Server!".to_string(),
+            port: 25565,
+            max_players: 20,
+            public: false,
+            salt: "abcdef0123456789".to_string(),
+            interval: Duration::from_secs(45),
+            users: Arc::new(AtomicUsize::new(3)),
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .build();
+        let url = heartbeat.send(&agent).unwrap();
+        assert_eq!(url, "http://list.test/play/abc");
+
+        let request_line = list.join().unwrap();
+        let target = request_line.split_whitespace().nth(1).unwrap();
+        let (path, query) = target.split_once('?').unwrap();
+        assert_eq!(path, "/server/heartbeat");
+        let query: HashMap<String, String> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (decode(k), decode(v)))
+            .collect();
+        assert_eq!(query["name"], "Test Server!");
+        assert_eq!(query["port"], "25565");
+        assert_eq!(query["users"], "3");
+        assert_eq!(query["max"], "20");
+        assert_eq!(query["salt"], "abcdef0123456789");
+        assert_eq!(query["public"], "False");
+        assert!(query["software"].starts_with(packets::SOFTWARE_NAME));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::classic_world::CreatedBy;
+use crate::World;
+use std::collections::HashMap;
+use std::io::Read;
+use std::rc::Rc;
+
+const LEVEL_MAGIC: u32 = 0x271B_B788;
+const STREAM_MAGIC: u32 = 0xACED_0005; // java serialization, version 5
+const BASE_HANDLE: u32 = 0x7E_0000;
+const MAX_BYTES: usize = 256 * 1024 * 1024; // largest byte array, enough for 1024x256x1024 blocks
+const MAX_STRING: usize = 1024 * 1024;
+const MAX_DEPTH: usize = 64; // objects and classes nested in each other
+
+// Type codes of the serialization stream
+const TC_NULL: u8 = 0x70;
+const TC_REFERENCE: u8 = 0x71;
+const TC_CLASSDESC: u8 = 0x72;
+const TC_OBJECT: u8 = 0x73;
+const TC_STRING: u8 = 0x74;
+const TC_ARRAY: u8 = 0x75;
+const TC_CLASS: u8 = 0x76;
+const TC_BLOCKDATA: u8 = 0x77;
+const TC_ENDBLOCKDATA: u8 = 0x78;
+const TC_RESET: u8 = 0x79;
+const TC_BLOCKDATALONG: u8 = 0x7A;
+const TC_LONGSTRING: u8 = 0x7C;
+const TC_PROXYCLASSDESC: u8 = 0x7D;
+const TC_ENUM: u8 = 0x7E;
+
+// Class descriptor flags
+const SC_WRITE_METHOD: u8 = 0x01;
+const SC_SERIALIZABLE: u8 = 0x02;
+const SC_EXTERNALIZABLE: u8 = 0x04;
+const SC_BLOCK_DATA: u8 = 0x08;
+
+/// Reads a gzip level of the original classic server (server.level or a .dat save).
+/// Version 1 files hold the blocks as is, version 2 a serialized `com.mojang.minecraft.level.Level`.
+pub fn read_level<R: Read>(r: &mut R) -> anyhow::Result<World> {
+    if read_u32(r)? != LEVEL_MAGIC {
+        return Err(anyhow::anyhow!("Not a classic level, wrong magic number"));
+    }
+    let mut level = match read_u8(r)? {
+        1 => read_raw_level(r)?,
+        2 => {
+            if read_u32(r)? != STREAM_MAGIC {
+                return Err(anyhow::anyhow!("Level is not a java serialization stream"));
+            }
+            let mut stream = Stream {
+                r,
+                handles: Vec::new(),
+                depth: 0,
+            };
+            let tc = read_u8(stream.r)?;
+            match stream.read_content(tc)? {
+                Value::Object(fields) => fields,
+                _ => return Err(anyhow::anyhow!("Level is not a serialized object")),
+            }
+        }
+        version => {
+            return Err(anyhow::anyhow!("Unsupported level version {}", version));
+        }
+    };
+
+    // The classic height is the length of the map, its depth the height
+    let number = |level: &HashMap<String, Value>, key: &str| match level.get(key) {
+        Some(Value::Number(n)) => Some(*n),
+        _ => None,
+    };
+    let size = (
+        number(&level, "width").unwrap_or(0.0) as i16,
+        number(&level, "depth").unwrap_or(0.0) as i16,
+        number(&level, "height").unwrap_or(0.0) as i16,
+    );
+    let blocks = match level.remove("blocks") {
+        Some(Value::Bytes(blocks)) => blocks,
+        _ => Vec::new(),
+    };
+    let count = size.0 as usize * size.1 as usize * size.2 as usize;
+    if size.0 <= 0 || size.1 <= 0 || size.2 <= 0 || blocks.len() != count {
+        return Err(anyhow::anyhow!(
+            "Level holds {} blocks, expected {} for {}x{}x{}",
+            blocks.len(),
+            count,
+            size.0,
+            size.1,
+            size.2
+        ));
+    }
+
+    let mut world = World::new(size.0, size.1, size.2);
+    world.blocks = blocks;
+    if let (Some(x), Some(y), Some(z)) = (
+        number(&level, "xSpawn"),
+        number(&level, "ySpawn"),
+        number(&level, "zSpawn"),
+    ) {
+        world.set_spawn((x as i16, y as i16, z as i16));
+    }
+    if let Some(rot) = number(&level, "rotSpawn") {
+        world.meta.spawn_yaw = (rot * 256.0 / 360.0) as i32 as u8; // degrees
+    }
+    if let Some(Value::String(name)) = level.remove("name") {
+        world.meta.name = Some(name);
+    }
+    if let Some(Value::String(creator)) = level.remove("creator") {
+        world.meta.created_by = Some(CreatedBy {
+            service: String::new(),
+            username: creator,
+            ..Default::default()
+        });
+    }
+    if let Some(time) = number(&level, "createTime") {
+        world.meta.time_created = Some(time as i64 / 1000); // milliseconds
+    }
+
+    Ok(world)
+}
+
+/// Fields of a version 1 level, which come one after the other.
+fn read_raw_level<R: Read>(r: &mut R) -> anyhow::Result<HashMap<String, Value>> {
+    let mut level = HashMap::new();
+    level.insert("name".into(), Value::String(read_utf(r, 2)?));
+    level.insert("creator".into(), Value::String(read_utf(r, 2)?));
+    level.insert("createTime".into(), Value::Number(read_i64(r)? as f64));
+    let mut size = 1;
+    for key in ["width", "height", "depth"] {
+        let mut b = [0u8; 2];
+        r.read_exact(&mut b)?;
+        let n = i16::from_be_bytes(b);
+        size *= n.max(0) as usize;
+        level.insert(key.into(), Value::Number(n as f64));
+    }
+    let blocks = read_bytes(r, size as u64, MAX_BYTES)?;
+    level.insert("blocks".into(), Value::Bytes(blocks));
+    Ok(level)
+}
+
+/// What the level is made of, other values are read but not kept.
+enum Value {
+    Null,
+    Number(f64), // any primitive
+    String(String),
+    Bytes(Vec<u8>), // byte[], like the blocks
+    Object(HashMap<String, Value>),
+}
+
+struct ClassDesc {
+    name: String,
+    flags: u8,
+    fields: Vec<(u8, String)>, // type code and name
+    super_class: Option<Rc<ClassDesc>>,
+}
+
+/// Objects met again are not needed to find the level fields, so only strings and classes are kept.
+enum Handle {
+    Class(Rc<ClassDesc>),
+    String(String),
+    Other,
+}
+
+struct Stream<'a, R: Read> {
+    r: &'a mut R,
+    handles: Vec<Handle>,
+    depth: usize, // of the content being read
+}
+
+impl<R: Read> Stream<'_, R> {
+    fn handle(&mut self) -> anyhow::Result<&Handle> {
+        let handle = read_u32(self.r)?;
+        let index = match handle.checked_sub(BASE_HANDLE) {
+            Some(index) => index as usize,
+            None => return Err(anyhow::anyhow!("Invalid handle {:#x}", handle)),
+        };
+        match self.handles.get(index) {
+            Some(handle) => Ok(handle),
+            None => Err(anyhow::anyhow!("Unknown handle {:#x}", handle)),
+        }
+    }
+
+    /// Runs `f` one level deeper, so that nesting can't overflow the stack.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(anyhow::anyhow!("Objects nested deeper than {}", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn read_content(&mut self, tc: u8) -> anyhow::Result<Value> {
+        self.nested(|stream| stream.content(tc))
+    }
+
+    fn content(&mut self, tc: u8) -> anyhow::Result<Value> {
+        match tc {
+            TC_NULL => Ok(Value::Null),
+            TC_REFERENCE => match self.handle()? {
+                Handle::String(s) => Ok(Value::String(s.clone())),
+                _ => Ok(Value::Null),
+            },
+            TC_STRING | TC_LONGSTRING => {
+                let s = read_utf(self.r, if tc == TC_STRING { 2 } else { 8 })?;
+                self.handles.push(Handle::String(s.clone()));
+                Ok(Value::String(s))
+            }
+            TC_OBJECT => self.read_object(),
+            TC_ARRAY => self.read_array(),
+            TC_ENUM => {
+                self.read_class_desc()?;
+                self.handles.push(Handle::Other);
+                let tc = read_u8(self.r)?;
+                self.read_content(tc) // name of the constant
+            }
+            TC_CLASS => {
+                self.read_class_desc()?;
+                self.handles.push(Handle::Other);
+                Ok(Value::Null)
+            }
+            TC_CLASSDESC | TC_PROXYCLASSDESC => {
+                self.read_class_desc_after(tc)?;
+                Ok(Value::Null)
+            }
+            TC_BLOCKDATA | TC_BLOCKDATALONG => {
+                let len = match tc {
+                    TC_BLOCKDATA => read_u8(self.r)? as u64,
+                    _ => read_u32(self.r)? as u64,
+                };
+                std::io::copy(&mut (&mut *self.r).take(len), &mut std::io::sink())?;
+                Ok(Value::Null)
+            }
+            TC_RESET => {
+                self.handles.clear();
+                let tc = read_u8(self.r)?;
+                self.read_content(tc)
+            }
+            tc => Err(anyhow::anyhow!("Unexpected type code {:#04x}", tc)),
+        }
+    }
+
+    fn read_class_desc(&mut self) -> anyhow::Result<Option<Rc<ClassDesc>>> {
+        let tc = read_u8(self.r)?;
+        self.read_class_desc_after(tc)
+    }
+
+    fn read_class_desc_after(&mut self, tc: u8) -> anyhow::Result<Option<Rc<ClassDesc>>> {
+        self.nested(|stream| stream.class_desc(tc))
+    }
+
+    fn class_desc(&mut self, tc: u8) -> anyhow::Result<Option<Rc<ClassDesc>>> {
+        match tc {
+            TC_NULL => Ok(None),
+            TC_REFERENCE => match self.handle()? {
+                Handle::Class(desc) => Ok(Some(desc.clone())),
+                _ => Err(anyhow::anyhow!("Reference is not a class")),
+            },
+            TC_CLASSDESC => {
+                let name = read_utf(self.r, 2)?;
+                read_i64(self.r)?; // serialVersionUID
+                let handle = self.handles.len();
+                self.handles.push(Handle::Other);
+                let flags = read_u8(self.r)?;
+
+                let mut b = [0u8; 2];
+                self.r.read_exact(&mut b)?;
+                let mut fields = Vec::new();
+                for _ in 0..u16::from_be_bytes(b) {
+                    let type_code = read_u8(self.r)?;
+                    let name = read_utf(self.r, 2)?;
+                    if type_code == b'[' || type_code == b'L' {
+                        let tc = read_u8(self.r)?;
+                        self.read_content(tc)?; // class name of the field
+                    }
+                    fields.push((type_code, name));
+                }
+                self.skip_annotation()?;
+                let super_class = self.read_class_desc()?;
+
+                let desc = Rc::new(ClassDesc {
+                    name,
+                    flags,
+                    fields,
+                    super_class,
+                });
+                self.handles[handle] = Handle::Class(desc.clone());
+                Ok(Some(desc))
+            }
+            TC_PROXYCLASSDESC => {
+                let handle = self.handles.len();
+                self.handles.push(Handle::Other);
+                for _ in 0..read_u32(self.r)? {
+                    read_utf(self.r, 2)?; // interface names
+                }
+                self.skip_annotation()?;
+                let super_class = self.read_class_desc()?;
+
+                let desc = Rc::new(ClassDesc {
+                    name: String::new(),
+                    flags: SC_SERIALIZABLE,
+                    fields: Vec::new(),
+                    super_class,
+                });
+                self.handles[handle] = Handle::Class(desc.clone());
+                Ok(Some(desc))
+            }
+            tc => Err(anyhow::anyhow!(
+                "Expected a class, got type code {:#04x}",
+                tc
+            )),
+        }
+    }
+
+    /// Extra data written by the class itself, up to the end of the block.
+    fn skip_annotation(&mut self) -> anyhow::Result<()> {
+        loop {
+            match read_u8(self.r)? {
+                TC_ENDBLOCKDATA => return Ok(()),
+                tc => self.read_content(tc)?,
+            };
+        }
+    }
+
+    fn read_object(&mut self) -> anyhow::Result<Value> {
+        let desc = match self.read_class_desc()? {
+            Some(desc) => desc,
+            None => return Err(anyhow::anyhow!("Object without a class")),
+        };
+        self.handles.push(Handle::Other);
+
+        // Fields of the super classes come first
+        let mut classes = Vec::new();
+        let mut class = Some(desc);
+        while let Some(desc) = class {
+            class = desc.super_class.clone();
+            classes.push(desc);
+        }
+
+        let mut fields = HashMap::new();
+        for desc in classes.iter().rev() {
+            if desc.flags & SC_EXTERNALIZABLE != 0 {
+                if desc.flags & SC_BLOCK_DATA == 0 {
+                    return Err(anyhow::anyhow!("Can't read externalizable {}", desc.name));
+                }
+                self.skip_annotation()?;
+                continue;
+            }
+            for (type_code, name) in desc.fields.iter() {
+                let value = self.read_value(*type_code)?;
+                fields.insert(name.clone(), value);
+            }
+            if desc.flags & SC_WRITE_METHOD != 0 {
+                self.skip_annotation()?;
+            }
+        }
+        Ok(Value::Object(fields))
+    }
+
+    fn read_array(&mut self) -> anyhow::Result<Value> {
+        let desc = match self.read_class_desc()? {
+            Some(desc) => desc,
+            None => return Err(anyhow::anyhow!("Array without a class")),
+        };
+        self.handles.push(Handle::Other);
+        let len = read_u32(self.r)?;
+
+        let type_code = desc.name.as_bytes().get(1).copied().unwrap_or(b'L');
+        if type_code == b'B' {
+            return Ok(Value::Bytes(read_bytes(self.r, len as u64, MAX_BYTES)?));
+        }
+        for _ in 0..len {
+            self.read_value(type_code)?;
+        }
+        Ok(Value::Null)
+    }
+
+    fn read_value(&mut self, type_code: u8) -> anyhow::Result<Value> {
+        let size = match type_code {
+            b'B' | b'Z' => 1,
+            b'C' | b'S' => 2,
+            b'I' | b'F' => 4,
+            b'J' | b'D' => 8,
+            b'[' | b'L' => {
+                let tc = read_u8(self.r)?;
+                return self.read_content(tc);
+            }
+            tc => return Err(anyhow::anyhow!("Unknown field type {}", tc as char)),
+        };
+
+        let mut b = [0u8; 8];
+        self.r.read_exact(&mut b[8 - size..])?;
+        let n = match type_code {
+            b'B' => b[7] as i8 as f64,
+            b'Z' => b[7] as f64,
+            b'C' => u16::from_be_bytes([b[6], b[7]]) as f64,
+            b'S' => i16::from_be_bytes([b[6], b[7]]) as f64,
+            b'I' => i32::from_be_bytes([b[4], b[5], b[6], b[7]]) as f64,
+            b'F' => f32::from_be_bytes([b[4], b[5], b[6], b[7]]) as f64,
+            b'J' => i64::from_be_bytes(b) as f64,
+            _ => f64::from_be_bytes(b),
+        };
+        Ok(Value::Number(n))
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> anyhow::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> anyhow::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_i64<R: Read>(r: &mut R) -> anyhow::Result<i64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(i64::from_be_bytes(b))
+}
+
+/// String prefixed by its length in `len_size` bytes, in java's modified utf-8.
+fn read_utf<R: Read>(r: &mut R, len_size: usize) -> anyhow::Result<String> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b[8 - len_size..])?;
+    let s = read_bytes(r, u64::from_be_bytes(b), MAX_STRING)?;
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+/// Reads `len` bytes, the buffer only grows with the data actually read.
+fn read_bytes<R: Read>(r: &mut R, len: u64, max: usize) -> anyhow::Result<Vec<u8>> {
+    if len > max as u64 {
+        return Err(anyhow::anyhow!(
+            "{} bytes is more than the {} allowed",
+            len,
+            max
+        ));
+    }
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(anyhow::anyhow!(
+            "Level ends {} bytes early",
+            len - bytes.len() as u64
+        ));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+
+    /// A 20x17x18 level written by java, with a block in two corners.
+    fn fixture() -> Vec<u8> {
+        let gz = include_bytes!("../tests/fixtures/server.level");
+        let mut level = Vec::new();
+        GzDecoder::new(&gz[..]).read_to_end(&mut level).unwrap();
+        level
+    }
+
+    /// A serialized level starting with `content`.
+    fn stream(content: &[u8]) -> Vec<u8> {
+        let mut level = LEVEL_MAGIC.to_be_bytes().to_vec();
+        level.push(2);
+        level.extend_from_slice(&STREAM_MAGIC.to_be_bytes());
+        level.extend_from_slice(content);
+        level
+    }
+
+    #[test]
+    fn reads_serialized_level() {
+        let world = read_level(&mut &fixture()[..]).unwrap();
+        assert_eq!((world.width, world.height, world.length), (20, 17, 18));
+        assert_eq!(world.get_block(1, 0, 1), Some(crate::block::STONE));
+        assert_eq!(world.get_block(19, 16, 17), Some(crate::block::BRICK));
+        assert_eq!(world.meta.name.as_deref(), Some("A Nice Level"));
+        assert_eq!(world.meta.created_by.unwrap().username, "notch");
+        assert_eq!(world.meta.time_created, Some(1250000000));
+        assert_eq!(world.meta.spawn_yaw, 64);
+    }
+
+    #[test]
+    fn rejects_truncated_levels() {
+        let level = fixture();
+        for len in 0..level.len() {
+            assert!(read_level(&mut &level[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut raw = LEVEL_MAGIC.to_be_bytes().to_vec();
+        raw.extend_from_slice(&[1, 0, 0, 0, 0]); // empty name and creator
+        raw.extend_from_slice(&[0; 8]);
+        raw.extend_from_slice(&[0x7f, 0xff, 0x7f, 0xff, 0x7f, 0xff]);
+
+        let mut byte_array = vec![TC_ARRAY, TC_CLASSDESC, 0, 2, b'[', b'B'];
+        byte_array.extend_from_slice(&[0; 8]);
+        byte_array.extend_from_slice(&[SC_SERIALIZABLE, 0, 0, TC_ENDBLOCKDATA, TC_NULL]);
+        byte_array.extend_from_slice(&[0xff; 4]);
+
+        let levels = [
+            raw,
+            stream(&[TC_REFERENCE, 0, 0, 0, 0]),
+            stream(&[TC_REFERENCE, 0x00, 0x7e, 0x00, 0x05]),
+            stream(&[
+                TC_LONGSTRING,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+            ]),
+            stream(&byte_array),
+            stream(&vec![TC_RESET; 100_000]),
+            stream(&[0xff; 64]),
+        ];
+        for level in levels.iter() {
+            assert!(read_level(&mut &level[..]).is_err());
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use crate::block::{self, BlockRegistry};
+use crate::World;
+use std::io::Read;
+
+const LVL_VERSION: u16 = 1874; // header of the current format, older maps start with the width
+const CUSTOM_BLOCK: u8 = 163; // the block is stored in the custom blocks section
+const SECTION_CUSTOM_BLOCKS: u8 = 0xBD;
+
+/// Reads a gzip MCSharp/MCGalaxy level (.lvl).
+/// Blocks missing from the registry are replaced by the block MCGalaxy shows in their place.
+pub fn read_lvl<R: Read>(r: &mut R, blocks: &BlockRegistry) -> anyhow::Result<World> {
+    let mut version = [0u8; 2];
+    r.read_exact(&mut version)?;
+
+    // width, length, height, spawn x, z, y, then spawn yaw and pitch
+    let mut header = [0u8; 14];
+    if u16::from_le_bytes(version) == LVL_VERSION {
+        r.read_exact(&mut header)?;
+        r.read_exact(&mut [0u8; 2])?; // visit and build permissions
+    } else {
+        header[..2].copy_from_slice(&version);
+        r.read_exact(&mut header[2..])?;
+    }
+    let short = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+    let (width, length, height) = (short(0), short(2), short(4));
+    let (spawn_x, spawn_z, spawn_y) = (short(6), short(8), short(10));
+    if width == 0
+        || height == 0
+        || length == 0
+        || [width, height, length].iter().any(|d| *d > i16::MAX as u16)
+    {
+        return Err(anyhow::anyhow!(
+            "Unsupported level size {}x{}x{}",
+            width,
+            height,
+            length
+        ));
+    }
+
+    let mut world = World::new(width as i16, height as i16, length as i16);
+    r.read_exact(&mut world.blocks)?;
+    for b in world.blocks.iter_mut() {
+        if *b != CUSTOM_BLOCK && !blocks.is_valid(*b) {
+            *b = mcgalaxy_block(*b);
+        }
+    }
+    world.set_spawn((spawn_x as i16, spawn_y as i16, spawn_z as i16));
+    world.meta.spawn_yaw = header[12];
+    world.meta.spawn_pitch = header[13];
+
+    // Sections follow the blocks, the ones after custom blocks (physics state) are skipped
+    let mut section = [0u8; 1];
+    while r.read(&mut section)? == 1 {
+        if section[0] != SECTION_CUSTOM_BLOCKS {
+            break;
+        }
+        read_custom_blocks(r, &mut world, blocks)?;
+    }
+
+    // Custom blocks without a section are unknown
+    for b in world.blocks.iter_mut() {
+        if *b == CUSTOM_BLOCK {
+            *b = block::STONE;
+        }
+    }
+    Ok(world)
+}
+
+/// Custom blocks are kept in chunks of 16x16x16, only chunks holding any are present.
+fn read_custom_blocks<R: Read>(
+    r: &mut R,
+    world: &mut World,
+    blocks: &BlockRegistry,
+) -> anyhow::Result<()> {
+    let (width, height, length) = (world.width as i32, world.height as i32, world.length as i32);
+    let mut chunk = vec![0u8; 16 * 16 * 16];
+
+    for cy in (0..height).step_by(16) {
+        for cz in (0..length).step_by(16) {
+            for cx in (0..width).step_by(16) {
+                let mut present = [0u8; 1];
+                r.read_exact(&mut present)?;
+                if present[0] != 1 {
+                    continue;
+                }
+                r.read_exact(&mut chunk)?;
+
+                for (i, custom) in chunk.iter().enumerate() {
+                    let i = i as i32;
+                    let (x, y, z) = (cx + i % 16, cy + i / 256, cz + i / 16 % 16);
+                    let (x, y, z) = (x as i16, y as i16, z as i16);
+                    // Chunks at the edges reach past the map
+                    if world.get_block(x, y, z) == Some(CUSTOM_BLOCK) {
+                        let custom = if blocks.is_valid(*custom) {
+                            *custom
+                        } else {
+                            block::STONE
+                        };
+                        world.set_block(x, y, z, custom)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// MCGalaxy's own blocks (physics, doors, portals, ...) as the block it shows to clients.
+/// Blocks without a known look become stone.
+fn mcgalaxy_block(id: u8) -> u8 {
+    use block::*;
+    match id {
+        73 | 112 => LAVA, // fast (hot) lava
+        74 => TNT,        // c4
+        75 => RED_WOOL,   // c4 detonator
+        100 => GLASS,     // op_glass
+        101 => OBSIDIAN,  // opsidian
+        102 => BRICK,
+        103 => STONE,
+        104 | 108 => COBBLESTONE,
+        105 => AIR,
+        106 => WATER,
+        107 => LAVA,
+        109 => SPONGE, // lava sponge
+        110 => PLANKS, // float wood
+        // doors, toggle doors (122-139) and the other doors (148-159) look like their base block
+        111 | 122 | 148 => LOG,
+        113 | 123 | 149 => OBSIDIAN,
+        114 | 124 | 150 => GLASS,
+        115 | 125 | 151 => STONE,
+        116 | 126 | 152 => LEAVES,
+        117 | 127 | 153 => SAND,
+        118 | 128 | 154 => PLANKS,
+        119 | 129 | 155 => GREEN_WOOL,
+        120 | 135 | 156 => TNT,
+        121 | 136 | 157 => SLAB,
+        137 | 158 => AIR,
+        138 | 159 => WATER,
+        139 => LAVA,
+        220 => IRON,
+        221 => DIRT,
+        222 => GRASS,
+        224 => BOOKSHELF,
+        // message blocks
+        130 => WHITE_WOOL,
+        131 => BLACK_WOOL,
+        132 => AIR,
+        133 => WATER,
+        134 => LAVA,
+        // active, finite and deadly liquids
+        140 | 145 | 161 | 166 | 190 | 193 | 196 => WATER,
+        141 | 146 | 162 | 167 | 184 | 185 | 191 | 194 | 195 => LAVA,
+        143 | 147 => CYAN_WOOL, // water faucets
+        144 => ORANGE_WOOL,     // lava faucet
+        // portals, air doors and switches, checkpoints and flood air
+        160 | 164 | 165 | 192 | 197 | 200..=204 => AIR,
+        175 => BLUE_WOOL,
+        176 => ORANGE_WOOL,
+        // explosives
+        182 | 183 | 186 | 231 => TNT,
+        187 => GLASS, // rocket start
+        188 => GOLD,  // rocket head
+        189 => IRON,  // fireworks
+        // animals
+        230 => AQUA_WOOL,         // train
+        232 => MOSSY_COBBLESTONE, // zombie body
+        233 => LIME_WOOL,         // zombie head
+        235 => WHITE_WOOL,
+        236 | 251 => BLACK_WOOL,
+        237 | 242 => LAVA,
+        238 | 247 => RED_WOOL,
+        239 => WATER,
+        240 | 245 => BLUE_WOOL,
+        246 => GOLD,
+        248 => GRAY_WOOL,
+        249 => SPONGE,
+        250 => OBSIDIAN,
+        252 => COAL_ORE, // snake tail
+        _ => STONE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_blocks_and_custom_blocks() {
+        let mut lvl = LVL_VERSION.to_le_bytes().to_vec();
+        // 16x16x16 with the spawn at 8, 2, 8
+        for v in [16u16, 16, 16, 8, 8, 2] {
+            lvl.extend_from_slice(&v.to_le_bytes());
+        }
+        lvl.extend_from_slice(&[64, 0, 0, 0]); // yaw, pitch and permissions
+
+        let mut blocks = vec![block::AIR; 16 * 16 * 16];
+        blocks[0] = block::STONE;
+        blocks[1] = block::SANDSTONE;
+        blocks[2] = 100; // op_glass
+        blocks[3] = 193; // deadly active water
+        blocks[4] = CUSTOM_BLOCK;
+        blocks[5] = CUSTOM_BLOCK;
+        lvl.extend_from_slice(&blocks);
+
+        let mut custom = vec![0u8; 16 * 16 * 16];
+        custom[4] = block::ICE;
+        custom[5] = 200; // without a definition
+        lvl.push(SECTION_CUSTOM_BLOCKS);
+        lvl.push(1);
+        lvl.extend_from_slice(&custom);
+        lvl.push(0xFC); // physics state, skipped
+
+        let world = read_lvl(&mut &lvl[..], &BlockRegistry::new()).unwrap();
+        assert_eq!((world.width, world.height, world.length), (16, 16, 16));
+        assert_eq!(world.meta.spawn_yaw, 64);
+        let expected = [
+            block::STONE,
+            block::SANDSTONE,
+            block::GLASS,
+            block::WATER,
+            block::ICE,
+            block::STONE,
+            block::AIR,
+        ];
+        assert_eq!(world.blocks[..7], expected);
+    }
+
+    #[test]
+    fn rejects_truncated_levels() {
+        let mut lvl = LVL_VERSION.to_le_bytes().to_vec();
+        for v in [16u16, 16, 16, 8, 8, 2] {
+            lvl.extend_from_slice(&v.to_le_bytes());
+        }
+        lvl.extend_from_slice(&[0; 100]);
+        assert!(read_lvl(&mut &lvl[..], &BlockRegistry::new()).is_err());
+    }
+}
@@ -1,8 +1,24 @@
+mod auth;
+mod bans;
+mod block;
+mod classic_world;
+mod commands;
+mod console;
+mod generator;
+mod heartbeat;
+mod java_level;
+mod lvl;
 mod nbt;
+mod noise;
 mod packets;
+mod physics;
+mod placement;
+mod rank;
 mod util;
+mod whitelist;
 mod world;
 use world::World;
+mod worlds;
 
 mod clock;
 use clock::Clock;
@@ -18,42 +34,57 @@ use config::Config;
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 fn main() -> anyhow::Result<()> {
-    // Ctrl-C handler
+    // Setup server specific stuff
+    let config = Config::new()?; // TODO(nv): specify arg to config file for loading?
+    let mut server = Server::new(config.clone())?;
+
+    // Ctrl-C handler, also wakes the server up if it's waiting for events
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
+    let waker = server.waker.clone();
     ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
+        let _ = waker.wake();
     })
     .expect("Error setting Ctrl-C handler");
 
-    // Setup server specific stuff
-    let config = Config::new()?; // TODO(nv): specify arg to config file for loading?
-    let mut clock = Clock::new(config.simulation.server_tick_rate as u128);
-    let mut server = Server::new(config.clone())?;
-
     println!("Started server!");
 
     loop {
         // Exit if not running
-        if !running.load(Ordering::SeqCst) {
+        if !running.load(Ordering::SeqCst) || server.stopping {
             if config.world.autosave {
-                server.world.save_world(config.world.path.clone())?;
+                server.worlds.save_all()?;
             }
             server.kick_players();
             break;
         }
 
+        server.unload_idle_worlds();
+
+        // Nothing to simulate without players, sleep until someone connects or a world is due to unload
+        if server.players.is_empty() {
+            server.poll(server.worlds.until_unload())?;
+            continue;
+        }
+
+        // Handle network events as they arrive until the next tick is due
+        server.poll(Some(server.clock.until_next_tick()))?;
+        if server.clock.until_next_tick() > Duration::from_millis(0) {
+            continue;
+        }
+
         // Start clocker
-        clock.start();
+        server.clock.start();
 
         // Progress server ticks
         server.tick()?;
-        println!("Players count: {}", server.players.len());
 
         // Count ticks
-        clock.finish_tick();
+        server.clock.finish_tick();
     }
 
     println!("Server closed!");
@@ -2,7 +2,10 @@ use crate::util::*;
 use std::collections::HashMap;
 use std::io::{Read, Write};
 
-#[derive(Clone, Debug)]
+const MAX_DEPTH: usize = 512; // lists and compounds nested in each other
+const MAX_CAPACITY: usize = 4096; // tags reserved up front, counts come from the file
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Tag {
     End,
     Byte(i8),
@@ -13,8 +16,10 @@ pub enum Tag {
     Double(f64),
     ByteArray(Vec<i8>),
     String(String),
-    List(Vec<Tag>),
+    List(i8, Vec<Tag>), // kind of the elements, kept for empty lists
     Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
 }
 
 impl Tag {
@@ -29,12 +34,17 @@ impl Tag {
             Tag::Double(_) => 0x06,
             Tag::ByteArray(_) => 0x07,
             Tag::String(_) => 0x08,
-            Tag::List(_) => 0x09,
+            Tag::List(..) => 0x09,
             Tag::Compound(_) => 0x0a,
+            Tag::IntArray(_) => 0x0b,
+            Tag::LongArray(_) => 0x0c,
         }
     }
 
-    fn read<R: Read>(reader: &mut R, tag_kind: i8) -> anyhow::Result<Tag> {
+    fn read<R: Read>(reader: &mut R, tag_kind: i8, depth: usize) -> anyhow::Result<Tag> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow::anyhow!("NBT nested deeper than {}", MAX_DEPTH));
+        }
         match tag_kind {
             0x00 => Ok(Tag::End),
             0x01 => Ok(Tag::Byte(read_sbyte(reader)?)),
@@ -44,21 +54,21 @@ impl Tag {
             0x05 => Ok(Tag::Float(read_float(reader)?)),
             0x06 => Ok(Tag::Double(read_double(reader)?)),
             0x07 => {
-                let count = read_int(reader)? as usize;
+                let count = read_count(reader)?;
                 Ok(Tag::ByteArray(read_bytearray(reader, count)?))
             }
             0x08 => Ok(Tag::String(read_utfstring(reader)?)),
             0x09 => {
                 let tag_kind = read_sbyte(reader)?;
 
-                let count = read_int(reader)? as usize;
-                let mut list = Vec::with_capacity(count);
+                let count = read_count(reader)?;
+                let mut list = Vec::with_capacity(count.min(MAX_CAPACITY));
 
                 for _ in 0..count {
-                    list.push(Tag::read(reader, tag_kind)?);
+                    list.push(Tag::read(reader, tag_kind, depth + 1)?);
                 }
 
-                Ok(Tag::List(list))
+                Ok(Tag::List(tag_kind, list))
             }
             0x0a => {
                 let mut m = HashMap::new();
@@ -70,11 +80,27 @@ impl Tag {
                     }
 
                     let key = read_utfstring(reader)?;
-                    let tag = Tag::read(reader, tag_kind)?;
+                    let tag = Tag::read(reader, tag_kind, depth + 1)?;
                     m.insert(key, tag);
                 }
                 Ok(Tag::Compound(m))
             }
+            0x0b => {
+                let count = read_count(reader)?;
+                let mut array = Vec::with_capacity(count.min(MAX_CAPACITY));
+                for _ in 0..count {
+                    array.push(read_int(reader)?);
+                }
+                Ok(Tag::IntArray(array))
+            }
+            0x0c => {
+                let count = read_count(reader)?;
+                let mut array = Vec::with_capacity(count.min(MAX_CAPACITY));
+                for _ in 0..count {
+                    array.push(read_long(reader)?);
+                }
+                Ok(Tag::LongArray(array))
+            }
             _ => Err(anyhow::anyhow!("Unknown tag kind!")),
         }
     }
@@ -94,9 +120,7 @@ impl Tag {
                 Ok(())
             }
             Tag::String(ref s) => write_utfstring(writer, s.clone()),
-            Tag::List(ref v) => {
-                let tag_kind = v.get(0).map(Tag::kind).unwrap_or(1);
-
+            Tag::List(tag_kind, ref v) => {
                 write_sbyte(writer, tag_kind)?;
                 write_int(writer, v.len() as i32)?;
 
@@ -116,11 +140,26 @@ impl Tag {
                 write_sbyte(writer, 0x00)?;
                 Ok(())
             }
+            Tag::IntArray(ref v) => {
+                write_int(writer, v.len() as i32)?;
+                for val in v.iter() {
+                    write_int(writer, *val)?;
+                }
+                Ok(())
+            }
+            Tag::LongArray(ref v) => {
+                write_int(writer, v.len() as i32)?;
+                for val in v.iter() {
+                    write_long(writer, *val)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)] // named after the format
+#[derive(Clone, Debug, PartialEq)]
 pub struct NBT {
     key: String,
     tag: Tag,
@@ -149,7 +188,7 @@ impl NBT {
         }
 
         let key = read_utfstring(reader)?;
-        let tag = Tag::read(reader, tag_kind)?;
+        let tag = Tag::read(reader, tag_kind, 0)?;
 
         Ok(NBT { key, tag })
     }
@@ -157,17 +196,71 @@ impl NBT {
     pub fn write<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
         write_sbyte(writer, 0x0a)?;
         write_utfstring(writer, self.key.clone())?;
-        self.tag.write(writer)?;
-        write_sbyte(writer, 0x00)?;
+        self.tag.write(writer)?; // the compound writes its own end tag
 
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn key(&self) -> &str {
         self.key.as_ref()
     }
 
+    #[allow(dead_code)]
     pub fn tag(&self) -> &Tag {
         &self.tag
     }
+
+    pub fn into_tag(self) -> Tag {
+        self.tag
+    }
+}
+
+/// Reads the length of an array or list, which is never negative.
+fn read_count<R: Read>(reader: &mut R) -> anyhow::Result<usize> {
+    let count = read_int(reader)?;
+    if count < 0 {
+        return Err(anyhow::anyhow!("Negative length {} in NBT!", count));
+    }
+    Ok(count as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_negative_lengths() {
+        // byte array, list of ints, int array and long array of length -1
+        for bytes in [
+            &[0x07, 0xff, 0xff, 0xff, 0xff][..],
+            &[0x09, 0x03, 0xff, 0xff, 0xff, 0xff][..],
+            &[0x0b, 0xff, 0xff, 0xff, 0xff][..],
+            &[0x0c, 0xff, 0xff, 0xff, 0xff][..],
+        ] {
+            let mut reader = &bytes[1..];
+            assert!(Tag::read(&mut reader, bytes[0] as i8, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_huge_lengths_without_the_data() {
+        // byte array, list of longs, int array and long array of length i32::MAX
+        for bytes in [
+            &[0x07, 0x7f, 0xff, 0xff, 0xff, 1, 2][..],
+            &[0x09, 0x04, 0x7f, 0xff, 0xff, 0xff, 1, 2][..],
+            &[0x0b, 0x7f, 0xff, 0xff, 0xff, 1, 2][..],
+            &[0x0c, 0x7f, 0xff, 0xff, 0xff, 1, 2][..],
+        ] {
+            let mut reader = &bytes[1..];
+            assert!(Tag::read(&mut reader, bytes[0] as i8, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        // lists holding a single list, far deeper than any real file
+        let bytes = [0x09, 0, 0, 0, 1].repeat(100_000);
+        assert!(Tag::read(&mut &bytes[..], 0x09, 0).is_err());
+    }
 }
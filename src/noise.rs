@@ -0,0 +1,164 @@
+/// The linear congruential generator of `java.util.Random`, the classic generator relies on its sequence.
+pub struct JavaRandom {
+    seed: i64,
+}
+
+impl JavaRandom {
+    pub fn new(seed: i64) -> Self {
+        JavaRandom {
+            seed: (seed ^ 0x5DEECE66D) & ((1 << 48) - 1),
+        }
+    }
+
+    fn next(&mut self, bits: u32) -> i32 {
+        self.seed = (self.seed.wrapping_mul(0x5DEECE66D).wrapping_add(0xB)) & ((1 << 48) - 1);
+        (self.seed >> (48 - bits)) as i32
+    }
+
+    /// Random number in `0..n`.
+    pub fn next_int(&mut self, n: i32) -> i32 {
+        if n <= 0 {
+            return 0;
+        }
+        if n & -n == n {
+            return ((n as i64 * self.next(31) as i64) >> 31) as i32;
+        }
+        loop {
+            let bits = self.next(31);
+            let val = bits % n;
+            if bits.wrapping_sub(val).wrapping_add(n - 1) >= 0 {
+                return val;
+            }
+        }
+    }
+
+    /// Random number in `min..max`.
+    pub fn range(&mut self, min: i32, max: i32) -> i32 {
+        min + self.next_int(max - min)
+    }
+
+    /// Random number in `0.0..1.0`.
+    pub fn next_float(&mut self) -> f32 {
+        self.next(24) as f32 / (1 << 24) as f32
+    }
+}
+
+/// Two dimensional improved Perlin noise.
+pub struct ImprovedNoise {
+    p: [u8; 512],
+}
+
+impl ImprovedNoise {
+    pub fn new(rnd: &mut JavaRandom) -> Self {
+        let mut p = [0u8; 512];
+        for (i, v) in p.iter_mut().enumerate().take(256) {
+            *v = i as u8;
+        }
+        for i in 0..256 {
+            let j = rnd.range(i as i32, 256) as usize;
+            p.swap(i, j);
+        }
+        for i in 0..256 {
+            p[i + 256] = p[i];
+        }
+        ImprovedNoise { p }
+    }
+
+    pub fn compute(&self, x: f64, y: f64) -> f64 {
+        let x_floor = x.floor();
+        let y_floor = y.floor();
+        let xi = (x_floor as i32 & 0xff) as usize;
+        let yi = (y_floor as i32 & 0xff) as usize;
+        let x = x - x_floor;
+        let y = y - y_floor;
+
+        let u = x * x * x * (x * (x * 6.0 - 15.0) + 10.0);
+        let v = y * y * y * (y * (y * 6.0 - 15.0) + 10.0);
+        // three dimensional Perlin noise with z fixed at 0, hence the extra lookup
+        let a = self.p[xi] as usize + yi;
+        let b = self.p[xi + 1] as usize + yi;
+        let (aa, ab) = (self.p[a] as usize, self.p[a + 1] as usize);
+        let (ba, bb) = (self.p[b] as usize, self.p[b + 1] as usize);
+
+        let g22 = grad(self.p[aa], x, y);
+        let g12 = grad(self.p[ba], x - 1.0, y);
+        let g21 = grad(self.p[ab], x, y - 1.0);
+        let g11 = grad(self.p[bb], x - 1.0, y - 1.0);
+        lerp(v, lerp(u, g22, g12), lerp(u, g21, g11))
+    }
+}
+
+fn grad(hash: u8, x: f64, y: f64) -> f64 {
+    let hash = hash & 15;
+    let u = if hash < 8 { x } else { y };
+    let v = if hash < 4 {
+        y
+    } else if hash == 12 || hash == 14 {
+        x
+    } else {
+        0.0
+    };
+    (if hash & 1 == 0 { u } else { -u }) + (if hash & 2 == 0 { v } else { -v })
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+/// Octaves of noise, each one twice as large and strong as the previous one.
+pub struct OctaveNoise {
+    octaves: Vec<ImprovedNoise>,
+}
+
+impl OctaveNoise {
+    pub fn new(rnd: &mut JavaRandom, count: usize) -> Self {
+        OctaveNoise {
+            octaves: (0..count).map(|_| ImprovedNoise::new(rnd)).collect(),
+        }
+    }
+
+    pub fn compute(&self, x: f64, y: f64) -> f64 {
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let mut sum = 0.0;
+        for octave in self.octaves.iter() {
+            sum += octave.compute(x * frequency, y * frequency) * amplitude;
+            amplitude *= 2.0;
+            frequency *= 0.5;
+        }
+        sum
+    }
+}
+
+/// Noise sampled at a position offset by a second noise.
+pub struct CombinedNoise {
+    noise1: OctaveNoise,
+    noise2: OctaveNoise,
+}
+
+impl CombinedNoise {
+    pub fn new(noise1: OctaveNoise, noise2: OctaveNoise) -> Self {
+        CombinedNoise { noise1, noise2 }
+    }
+
+    pub fn compute(&self, x: f64, y: f64) -> f64 {
+        let offset = self.noise2.compute(x, y);
+        self.noise1.compute(x + offset, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_int_matches_java() {
+        // large bounds reject about half of the numbers, which relies on wrapping arithmetic
+        let mut rnd = JavaRandom::new(42);
+        let ints: Vec<i32> = (0..5).map(|_| rnd.next_int(1073741825)).collect();
+        assert_eq!(
+            ints,
+            [117392763, 102948884, 662969970, 595021505, 196118093]
+        );
+    }
+}
@@ -1,8 +1,18 @@
 use crate::util::*;
+use std::fmt;
 use std::io::{Read, Write};
 
 pub const PROTOCOL_VERSION: u8 = 0x7;
 
+/// Value of the identification's unused byte sent by clients supporting the Classic Protocol Extension.
+pub const CPE_MAGIC: u8 = 0x42;
+pub const SOFTWARE_NAME: &str = "qubiq";
+
+/// Extensions supported by the server with their versions, announced to CPE clients.
+pub const EXTENSIONS: &[(&str, i32)] = &[("EmoteFix", 1), ("MessageTypes", 1), ("CustomBlocks", 1)];
+/// Highest level of CustomBlocks supported, level 1 adds blocks 50 to 65.
+pub const CUSTOM_BLOCKS_LEVEL: u8 = 1;
+
 const SERVER_LEVEL_INIT: u8 = 0x02;
 const SERVER_LEVEL_DATA: u8 = 0x03;
 const SERVER_LEVEL_FINAL: u8 = 0x04;
@@ -16,10 +26,91 @@ pub const CS_IDENTIFICATION: u8 = 0x00;
 pub const CS_PING_PONG: u8 = 0x01;
 pub const CS_POSITION_ORIENTATION: u8 = 0x08;
 pub const CS_MESSAGE: u8 = 0x0d;
+pub const CS_EXT_INFO: u8 = 0x10;
+pub const CS_EXT_ENTRY: u8 = 0x11;
+pub const CS_CUSTOM_BLOCK_SUPPORT_LEVEL: u8 = 0x13;
 
 pub const CLIENT_BLOCK: u8 = 0x05;
 
+/// Maximum amount of received bytes buffered for a single player before it is kicked.
+pub const MAX_INBOUND_BYTES: usize = 64 * 1024;
+/// Maximum number of extensions a CPE client may announce in its ExtInfo.
+const MAX_EXTENSIONS: i16 = 256;
+
+/// Protocol violations of a client, the message is used as the kick reason.
+#[derive(Debug)]
+pub enum PacketError {
+    UnknownPacket(u8),
+    Oversize(usize),
+    Malformed { packet_id: u8, reason: String },
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::UnknownPacket(packet_id) => {
+                write!(f, "Unknown packet id: 0x{:02x}", packet_id)
+            }
+            PacketError::Oversize(size) => write!(f, "Too much data sent: {} bytes", size),
+            PacketError::Malformed { packet_id, reason } => {
+                write!(f, "Malformed packet 0x{:02x}: {}", packet_id, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+/// Returns the full size in bytes, opcode included, of a packet sent by the client.
+/// Unknown opcodes have no size, so the rest of the stream can't be framed.
+pub fn packet_length(packet_id: u8) -> Option<usize> {
+    match packet_id {
+        CS_IDENTIFICATION => Some(131),
+        CS_PING_PONG => Some(1),
+        CLIENT_BLOCK => Some(9),
+        CS_POSITION_ORIENTATION => Some(10),
+        CS_MESSAGE => Some(66),
+        CS_EXT_INFO => Some(67),
+        CS_EXT_ENTRY => Some(69),
+        CS_CUSTOM_BLOCK_SUPPORT_LEVEL => Some(2),
+        _ => None,
+    }
+}
+
+/// Decodes the first packet of the buffer and returns it with its size in bytes.
+/// Returns `None` while the packet is still incomplete.
+pub fn decode_packet(buf: &[u8]) -> Result<Option<(ClientPacket, usize)>, PacketError> {
+    let packet_id = match buf.first() {
+        Some(packet_id) => *packet_id,
+        None => return Ok(None),
+    };
+    let length = packet_length(packet_id).ok_or(PacketError::UnknownPacket(packet_id))?;
+    if buf.len() < length {
+        return Ok(None);
+    }
+
+    let mut reader = &buf[1..length];
+    let packet = match packet_id {
+        CS_IDENTIFICATION => handle_player_identification(&mut reader),
+        CS_PING_PONG => Ok(ClientPacket::Ping),
+        CLIENT_BLOCK => handle_set_block(&mut reader),
+        CS_POSITION_ORIENTATION => handle_position_and_orientation(&mut reader),
+        CS_MESSAGE => handle_player_message(&mut reader),
+        CS_EXT_INFO => handle_ext_info(&mut reader),
+        CS_EXT_ENTRY => handle_ext_entry(&mut reader),
+        CS_CUSTOM_BLOCK_SUPPORT_LEVEL => handle_custom_block_support_level(&mut reader),
+        _ => return Err(PacketError::UnknownPacket(packet_id)),
+    }
+    .map_err(|e| PacketError::Malformed {
+        packet_id,
+        reason: e.to_string(),
+    })?;
+
+    Ok(Some((packet, length)))
+}
+
 pub enum ClientPacket {
+    Ping,
     PlayerAuth {
         protocol_version: u8,
         username: String,
@@ -38,6 +129,15 @@ pub enum ClientPacket {
         mode: u8,
         block_type: u8,
     },
+    ExtInfo {
+        app_name: String,
+        extension_count: i16,
+    },
+    ExtEntry {
+        ext_name: String,
+        version: i32,
+    },
+    CustomBlockSupportLevel(u8),
 }
 
 pub fn handle_player_identification<R: Read>(reader: &mut R) -> anyhow::Result<ClientPacket> {
@@ -46,10 +146,6 @@ pub fn handle_player_identification<R: Read>(reader: &mut R) -> anyhow::Result<C
     let username = read_mcstring(reader)?;
     let verification_key = read_mcstring(reader)?;
     let unused = read_byte(reader)?;
-    println!("Protocol version: {}", protocol_version);
-    println!("Username: {}", username);
-    println!("Key: {}", verification_key);
-    println!("Unused: {}", unused);
 
     Ok(ClientPacket::PlayerAuth {
         protocol_version,
@@ -64,8 +160,6 @@ pub fn handle_player_message<R: Read>(reader: &mut R) -> anyhow::Result<ClientPa
     let _unused = read_byte(reader)?;
     let message = read_mcstring(reader)?;
 
-    println!("Client message: {}", &message);
-
     // Replace % to be colored
     let mut back_message = message.replace("%", "&");
     // Sanitize string, if it contains & at end it crashes.
@@ -106,6 +200,35 @@ pub fn handle_position_and_orientation<R: Read>(reader: &mut R) -> anyhow::Resul
     })
 }
 
+pub fn handle_ext_info<R: Read>(reader: &mut R) -> anyhow::Result<ClientPacket> {
+    let app_name = read_mcstring(reader)?;
+    let extension_count = read_short(reader)?;
+    if !(0..=MAX_EXTENSIONS).contains(&extension_count) {
+        return Err(anyhow::anyhow!(
+            "invalid extension count {}",
+            extension_count
+        ));
+    }
+
+    Ok(ClientPacket::ExtInfo {
+        app_name,
+        extension_count,
+    })
+}
+
+pub fn handle_ext_entry<R: Read>(reader: &mut R) -> anyhow::Result<ClientPacket> {
+    let ext_name = read_mcstring(reader)?;
+    let version = read_int(reader)?;
+
+    Ok(ClientPacket::ExtEntry { ext_name, version })
+}
+
+pub fn handle_custom_block_support_level<R: Read>(reader: &mut R) -> anyhow::Result<ClientPacket> {
+    let level = read_byte(reader)?;
+    Ok(ClientPacket::CustomBlockSupportLevel(level))
+}
+
+#[derive(Clone)]
 pub enum ServerPacket<'a> {
     ServerInfo {
         operator: u8,
@@ -144,6 +267,15 @@ pub enum ServerPacket<'a> {
     Message(String),
     Kick(String),
     UpdateUserType(u8),
+    ExtInfo {
+        app_name: String,
+        extension_count: i16,
+    },
+    ExtEntry {
+        ext_name: String,
+        version: i32,
+    },
+    CustomBlockSupportLevel(u8),
 }
 
 pub fn server_info<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow::Result<()> {
@@ -234,7 +366,7 @@ pub fn level_chunk_data<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow:
         write_short(writer, length)?; // chunk length
 
         // Chunk must be fixed size of 1024 bytes, fill the rest
-        writer.write(data)?;
+        writer.write_all(data)?;
         for _i in 0..1024 - length {
             write_byte(writer, 0x00)?;
         }
@@ -274,6 +406,15 @@ pub fn broadcast_block<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow::
     Ok(())
 }
 
+pub fn update_user_type<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow::Result<()> {
+    if let ServerPacket::UpdateUserType(user_type) = data {
+        write_byte(writer, SERVER_USER_TYPE)?;
+        write_byte(writer, user_type)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
 pub fn ping<W: Write>(writer: &mut W) -> anyhow::Result<()> {
     write_byte(writer, CS_PING_PONG)?;
     writer.flush()?;
@@ -298,3 +439,125 @@ pub fn broadcast_message<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow
     }
     Ok(())
 }
+
+pub fn ext_info<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow::Result<()> {
+    if let ServerPacket::ExtInfo {
+        app_name,
+        extension_count,
+    } = data
+    {
+        write_byte(writer, CS_EXT_INFO)?;
+        write_mcstring(writer, app_name)?;
+        write_short(writer, extension_count)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+pub fn ext_entry<W: Write>(writer: &mut W, data: ServerPacket) -> anyhow::Result<()> {
+    if let ServerPacket::ExtEntry { ext_name, version } = data {
+        write_byte(writer, CS_EXT_ENTRY)?;
+        write_mcstring(writer, ext_name)?;
+        write_int(writer, version)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+pub fn custom_block_support_level<W: Write>(
+    writer: &mut W,
+    data: ServerPacket,
+) -> anyhow::Result<()> {
+    if let ServerPacket::CustomBlockSupportLevel(level) = data {
+        write_byte(writer, CS_CUSTOM_BLOCK_SUPPORT_LEVEL)?;
+        write_byte(writer, level)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identification(unused: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_byte(&mut buf, CS_IDENTIFICATION).unwrap();
+        write_byte(&mut buf, PROTOCOL_VERSION).unwrap();
+        write_mcstring(&mut buf, "Notch".to_string()).unwrap();
+        write_mcstring(&mut buf, "key".to_string()).unwrap();
+        write_byte(&mut buf, unused).unwrap();
+        buf
+    }
+
+    #[test]
+    fn decodes_complete_packet() {
+        let buf = identification(CPE_MAGIC);
+        match decode_packet(&buf) {
+            Ok(Some((
+                ClientPacket::PlayerAuth {
+                    username, unused, ..
+                },
+                length,
+            ))) => {
+                assert_eq!(length, buf.len());
+                assert_eq!(username.trim_end_matches('\0'), "Notch");
+                assert_eq!(unused, CPE_MAGIC);
+            }
+            _ => panic!("identification not decoded"),
+        }
+    }
+
+    #[test]
+    fn waits_for_partial_packet() {
+        let buf = identification(0);
+        for end in 0..buf.len() {
+            assert!(matches!(decode_packet(&buf[..end]), Ok(None)));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_packet() {
+        let buf = [0xff, 0x00, 0x01];
+        assert!(matches!(
+            decode_packet(&buf),
+            Err(PacketError::UnknownPacket(0xff))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_extension_count() {
+        let mut buf = Vec::new();
+        write_byte(&mut buf, CS_EXT_INFO).unwrap();
+        write_mcstring(&mut buf, "Garbage".to_string()).unwrap();
+        write_short(&mut buf, -1).unwrap();
+        assert!(matches!(
+            decode_packet(&buf),
+            Err(PacketError::Malformed {
+                packet_id: CS_EXT_INFO,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn garbage_streams_never_panic() {
+        // Simple LCG so the garbage is the same on every run
+        let mut seed = 0x2545_f491_u32;
+        for _ in 0..1000 {
+            let mut buf = Vec::new();
+            for _ in 0..256 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                buf.push((seed >> 16) as u8);
+            }
+
+            // Decode like a player tick does, until more data is needed or the client gets kicked
+            let mut offset = 0;
+            while let Ok(Some((_, length))) = decode_packet(&buf[offset..]) {
+                assert!(length > 0);
+                offset += length;
+            }
+            assert!(offset <= buf.len());
+        }
+    }
+}
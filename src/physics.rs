@@ -0,0 +1,536 @@
+use crate::block::{self, BlockRegistry};
+use crate::config::SimulationCfg;
+use crate::server::Queue;
+use crate::World;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::VecDeque;
+
+/// Most block updates handled in one tick, the rest are left for the next ones.
+const MAX_UPDATES_PER_TICK: usize = 10_000;
+
+/// Directions liquids spread in, everywhere but up.
+const FLOW: [(i16, i16, i16); 5] = [(0, -1, 0), (1, 0, 0), (-1, 0, 0), (0, 0, 1), (0, 0, -1)];
+
+const NEIGHBOURS: [(i16, i16, i16); 6] = [
+    (0, 1, 0),
+    (0, -1, 0),
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+];
+
+/// Block physics, only blocks that changed or lost a neighbour are looked at.
+/// Slow changes like growing grass happen to blocks picked at random instead.
+pub struct Physics {
+    sand_delay: u64,  // ticks between each block a falling block drops
+    water_delay: u64, // ticks between each spread of flowing water
+    lava_delay: u64,
+    finite_liquids: bool,
+    sponge_radius: i16,
+    random_delay: u64, // ticks between random ticks
+    random_speed: u32, // blocks picked in each 16x16x16 blocks every random tick
+}
+
+impl Physics {
+    pub fn new(simulation: &SimulationCfg) -> Self {
+        Physics {
+            sand_delay: ticks(simulation.sand_tick_rate, simulation),
+            water_delay: ticks(simulation.water_tick_rate, simulation),
+            lava_delay: ticks(simulation.lava_tick_rate, simulation),
+            finite_liquids: simulation.finite_liquids,
+            sponge_radius: simulation.sponge_radius,
+            random_delay: ticks(simulation.random_tick_rate, simulation),
+            random_speed: simulation.random_tick_speed,
+        }
+    }
+
+    /// Schedules updates for a changed block and its neighbours, blocks without physics are skipped.
+    pub fn block_changed(
+        &self,
+        world: &mut World,
+        blocks: &BlockRegistry,
+        coords: (i16, i16, i16),
+        previous: u8,
+    ) {
+        let around = std::iter::once((0, 0, 0)).chain(NEIGHBOURS.iter().copied());
+        for near in around.filter_map(|d| offset(coords, d)) {
+            if let Some(delay) = self.delay(world, blocks, near) {
+                world.schedule(near, delay);
+            }
+        }
+
+        // Water kept away by a removed sponge flows back, from just outside of its radius too
+        if previous == block::SPONGE
+            && world.get_block(coords.0, coords.1, coords.2) != Some(block::SPONGE)
+        {
+            let r = self.sponge_radius + 1;
+            for near in cube(coords, r) {
+                if let Some(delay) = self.delay(world, blocks, near) {
+                    world.schedule(near, delay);
+                }
+            }
+        }
+    }
+
+    /// Runs the block updates that are due, changed blocks are queued to be broadcast.
+    pub fn tick(&self, world: &mut World, blocks: &BlockRegistry, queue: &mut VecDeque<Queue>) {
+        for coords in world.due_updates(MAX_UPDATES_PER_TICK) {
+            self.update(world, blocks, queue, coords);
+        }
+
+        if world.ticks().is_multiple_of(self.random_delay) {
+            let volume = world.blocks.len() as u64;
+            let count = (volume * self.random_speed as u64).div_ceil(16 * 16 * 16);
+            let mut rng = rand::thread_rng();
+            for _ in 0..count {
+                let coords = (
+                    rng.gen_range(0, world.width),
+                    rng.gen_range(0, world.height),
+                    rng.gen_range(0, world.length),
+                );
+                self.random_tick(world, blocks, queue, coords);
+            }
+        }
+    }
+
+    /// Grass spreads to lit dirt next to it and dies when covered, saplings grow into trees.
+    fn random_tick(
+        &self,
+        world: &mut World,
+        blocks: &BlockRegistry,
+        queue: &mut VecDeque<Queue>,
+        coords: (i16, i16, i16),
+    ) {
+        let (x, y, z) = coords;
+        match world.get_block(x, y, z) {
+            Some(block::DIRT) if lit(world, blocks, coords) && near_grass(world, coords) => {
+                set_block(world, queue, coords, block::GRASS)
+            }
+            Some(block::GRASS) if !lit(world, blocks, coords) => {
+                set_block(world, queue, coords, block::DIRT)
+            }
+            Some(block::SAPLING) => match world.get_block(x, y - 1, z) {
+                Some(block::DIRT) | Some(block::GRASS) if lit(world, blocks, coords) => {
+                    grow_tree(world, queue, coords)
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn delay(&self, world: &World, blocks: &BlockRegistry, coords: (i16, i16, i16)) -> Option<u64> {
+        let (x, y, z) = coords;
+        let block = blocks.get(world.get_block(x, y, z)?)?;
+        match block.id {
+            block::WATER | block::STILL_WATER => Some(self.water_delay),
+            block::LAVA | block::STILL_LAVA => Some(self.lava_delay),
+            block::SPONGE => Some(1),
+            _ if block.gravity => Some(self.sand_delay),
+            _ => None,
+        }
+    }
+
+    fn update(
+        &self,
+        world: &mut World,
+        blocks: &BlockRegistry,
+        queue: &mut VecDeque<Queue>,
+        coords: (i16, i16, i16),
+    ) {
+        let (x, y, z) = coords;
+        let block_type = match world.get_block(x, y, z) {
+            Some(block_type) => block_type,
+            None => return,
+        };
+        let block = match blocks.get(block_type) {
+            Some(block) => block,
+            None => return,
+        };
+
+        // Sand and gravel drop one block at a time through anything that isn't solid
+        if block.gravity {
+            let below = match world.get_block(x, y - 1, z).and_then(|b| blocks.get(b)) {
+                Some(below) => below,
+                None => return,
+            };
+            if !below.solid {
+                set_block(world, queue, coords, block::AIR);
+                set_block(world, queue, (x, y - 1, z), block_type);
+            }
+            return;
+        }
+
+        match block_type {
+            _ if block.liquid => self.flow(world, queue, coords, block_type),
+            block::SPONGE => self.absorb(world, queue, coords),
+            _ => {}
+        }
+    }
+
+    /// Spreads water or lava, still liquids turn into flowing ones once they have room to spread.
+    fn flow(
+        &self,
+        world: &mut World,
+        queue: &mut VecDeque<Queue>,
+        coords: (i16, i16, i16),
+        block_type: u8,
+    ) {
+        let liquid = match block_type {
+            block::STILL_WATER => block::WATER,
+            block::STILL_LAVA => block::LAVA,
+            liquid => liquid,
+        };
+
+        // Water and lava meeting harden the lava, a lava source turns into obsidian
+        for near in NEIGHBOURS.iter().filter_map(|d| offset(coords, *d)) {
+            let hardened = match (liquid, world.get_block(near.0, near.1, near.2)) {
+                (block::WATER, Some(block::LAVA)) => Some((near, block::STONE)),
+                (block::WATER, Some(block::STILL_LAVA)) => Some((near, block::OBSIDIAN)),
+                (block::LAVA, Some(block::WATER)) | (block::LAVA, Some(block::STILL_WATER)) => {
+                    let hardened = match block_type {
+                        block::STILL_LAVA => block::OBSIDIAN,
+                        _ => block::STONE,
+                    };
+                    Some((coords, hardened))
+                }
+                _ => None,
+            };
+            if let Some((at, block_type)) = hardened {
+                set_block(world, queue, at, block_type);
+                if at == coords {
+                    return;
+                }
+            }
+        }
+
+        let free = |world: &World, (x, y, z): (i16, i16, i16)| {
+            world.get_block(x, y, z) == Some(block::AIR)
+                && !(liquid == block::WATER && self.near_sponge(world, (x, y, z)))
+        };
+
+        if self.finite_liquids {
+            // The liquid moves instead of spreading, down first, else over an edge next to it
+            let below = offset(coords, FLOW[0]);
+            let target = if below.is_some_and(|below| free(world, below)) {
+                below
+            } else {
+                let edges = FLOW[1..]
+                    .iter()
+                    .filter_map(|d| offset(coords, *d))
+                    .filter(|&near| {
+                        free(world, near)
+                            && offset(near, FLOW[0]).is_some_and(|below| free(world, below))
+                    })
+                    .collect::<Vec<_>>();
+                edges.choose(&mut rand::thread_rng()).copied()
+            };
+            if let Some(target) = target {
+                set_block(world, queue, coords, block::AIR);
+                set_block(world, queue, target, liquid);
+            }
+            return;
+        }
+
+        let targets = FLOW
+            .iter()
+            .filter_map(|d| offset(coords, *d))
+            .filter(|&near| free(world, near))
+            .collect::<Vec<_>>();
+        if !targets.is_empty() && block_type != liquid {
+            set_block(world, queue, coords, liquid);
+        }
+        for near in targets {
+            set_block(world, queue, near, liquid);
+        }
+    }
+
+    /// Removes the water around a sponge.
+    fn absorb(&self, world: &mut World, queue: &mut VecDeque<Queue>, coords: (i16, i16, i16)) {
+        for near in cube(coords, self.sponge_radius) {
+            match world.get_block(near.0, near.1, near.2) {
+                Some(block::WATER) | Some(block::STILL_WATER) => {
+                    set_block(world, queue, near, block::AIR)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn near_sponge(&self, world: &World, coords: (i16, i16, i16)) -> bool {
+        cube(coords, self.sponge_radius)
+            .any(|(x, y, z)| world.get_block(x, y, z) == Some(block::SPONGE))
+    }
+}
+
+/// Coordinates moved by `d`, None if they leave the range of i16.
+fn offset(coords: (i16, i16, i16), d: (i16, i16, i16)) -> Option<(i16, i16, i16)> {
+    Some((
+        coords.0.checked_add(d.0)?,
+        coords.1.checked_add(d.1)?,
+        coords.2.checked_add(d.2)?,
+    ))
+}
+
+/// Every coordinate at most `r` blocks away on each axis.
+fn cube(coords: (i16, i16, i16), r: i16) -> impl Iterator<Item = (i16, i16, i16)> {
+    (-r..=r).flat_map(move |dy| {
+        (-r..=r).flat_map(move |dz| (-r..=r).filter_map(move |dx| offset(coords, (dx, dy, dz))))
+    })
+}
+
+/// Returns true if every block above lets light through.
+fn lit(world: &World, blocks: &BlockRegistry, coords: (i16, i16, i16)) -> bool {
+    let (x, y, z) = coords;
+    (y + 1..world.height).all(
+        |y| match world.get_block(x, y, z).and_then(|b| blocks.get(b)) {
+            Some(block) => block.transmits_light,
+            None => false,
+        },
+    )
+}
+
+/// Returns true if grass is close enough to spread to the dirt, as in classic:
+/// one block away sideways, from one block below up to three blocks above.
+fn near_grass(world: &World, coords: (i16, i16, i16)) -> bool {
+    (-1..=3).any(|dy| {
+        (-1..=1).any(|dz| {
+            (-1..=1).any(|dx| {
+                offset(coords, (dx, dy, dz))
+                    .is_some_and(|(x, y, z)| world.get_block(x, y, z) == Some(block::GRASS))
+            })
+        })
+    })
+}
+
+/// Grows a tree from a sapling, nothing happens unless there is room for the whole tree.
+fn grow_tree(world: &mut World, queue: &mut VecDeque<Queue>, coords: (i16, i16, i16)) {
+    let (x, y, z) = coords;
+    let mut rng = rand::thread_rng();
+    let height = rng.gen_range(4, 7);
+    if offset(coords, (2, height + 1, 2)).is_none() {
+        return; // the tree would reach past the largest coordinates
+    }
+    let top = y + height - 1;
+
+    // Two wide layers of leaves around the trunk, then two narrow ones on top
+    let mut tree = Vec::new();
+    for ly in top - 2..=top + 1 {
+        let radius = if ly < top { 2 } else { 1 };
+        for lz in -radius..=radius {
+            for lx in -radius..=radius {
+                // Corners are cut off at random
+                let corner = lx * lx == radius * radius && lz * lz == radius * radius;
+                if corner && (ly > top || rng.gen_bool(0.5)) {
+                    continue;
+                }
+                tree.push(((x + lx, ly, z + lz), block::LEAVES));
+            }
+        }
+    }
+    for ty in y..=top {
+        tree.retain(|(at, _)| *at != (x, ty, z));
+        tree.push(((x, ty, z), block::LOG));
+    }
+
+    let room = tree.iter().all(|&((tx, ty, tz), _)| {
+        (tx, ty, tz) == coords || world.get_block(tx, ty, tz) == Some(block::AIR)
+    });
+    if !room {
+        return;
+    }
+    for (at, block_type) in tree {
+        set_block(world, queue, at, block_type);
+    }
+}
+
+/// Converts a rate in milliseconds to server ticks, at least one.
+fn ticks(millis: u64, simulation: &SimulationCfg) -> u64 {
+    millis.div_ceil(simulation.server_tick_rate.max(1)).max(1)
+}
+
+fn set_block(
+    world: &mut World,
+    queue: &mut VecDeque<Queue>,
+    coords: (i16, i16, i16),
+    block_type: u8,
+) {
+    let (x, y, z) = coords;
+    let previous = match world.get_block(x, y, z) {
+        Some(previous) => previous,
+        None => return,
+    };
+    if world.set_block(x, y, z, block_type).is_ok() {
+        queue.push_back(Queue::SetBlock {
+            world: world.name.clone(),
+            coords,
+            block_type,
+            previous,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Physics updating every block each tick, without random ticks.
+    fn physics() -> Physics {
+        Physics::new(&SimulationCfg {
+            sand_tick_rate: 50,
+            water_tick_rate: 50,
+            lava_tick_rate: 50,
+            random_tick_speed: 0,
+            ..Default::default()
+        })
+    }
+
+    /// 16x16x16 world with a floor of stone.
+    fn world() -> World {
+        let mut world = World::new(16, 16, 16);
+        fill(&mut world, 0, 16, 0, block::STONE);
+        world
+    }
+
+    /// Sets the blocks of a layer with x in `x0..x1`.
+    fn fill(world: &mut World, x0: i16, x1: i16, y: i16, block_type: u8) {
+        for z in 0..16 {
+            for x in x0..x1 {
+                world.set_block(x, y, z, block_type).unwrap();
+            }
+        }
+    }
+
+    /// Changes a block like a player does.
+    fn change(physics: &Physics, world: &mut World, coords: (i16, i16, i16), block_type: u8) {
+        let (x, y, z) = coords;
+        let previous = world.get_block(x, y, z).unwrap();
+        world.set_block(x, y, z, block_type).unwrap();
+        physics.block_changed(world, &BlockRegistry::new(), coords, previous);
+    }
+
+    /// Runs physics ticks, changes wake up their neighbours as they do on the server.
+    fn run(physics: &Physics, world: &mut World, ticks: usize) {
+        let blocks = BlockRegistry::new();
+        for _ in 0..ticks {
+            let mut queue = VecDeque::new();
+            physics.tick(world, &blocks, &mut queue);
+            for change in queue {
+                if let Queue::SetBlock {
+                    coords, previous, ..
+                } = change
+                {
+                    physics.block_changed(world, &blocks, coords, previous);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sand_falls_into_a_gap() {
+        let (physics, mut world) = (physics(), world());
+        for y in 1..4 {
+            fill(&mut world, 0, 16, y, block::STONE);
+            world.set_block(8, y, 8, block::AIR).unwrap();
+        }
+        world.set_block(8, 1, 8, block::ROSE).unwrap();
+        change(&physics, &mut world, (8, 3, 8), block::SAND);
+        change(&physics, &mut world, (9, 4, 8), block::GRAVEL);
+        run(&physics, &mut world, 5);
+        assert_eq!(world.get_block(8, 1, 8), Some(block::SAND));
+        assert_eq!(world.get_block(8, 3, 8), Some(block::AIR));
+        assert_eq!(world.get_block(9, 4, 8), Some(block::GRAVEL)); // resting on stone
+    }
+
+    #[test]
+    fn water_spreads_over_the_floor() {
+        let (physics, mut world) = (physics(), world());
+        change(&physics, &mut world, (8, 1, 8), block::WATER);
+        run(&physics, &mut world, 20);
+        assert_eq!(world.get_block(0, 1, 0), Some(block::WATER));
+        assert_eq!(world.get_block(15, 1, 15), Some(block::WATER));
+        assert_eq!(world.get_block(8, 2, 8), Some(block::AIR));
+    }
+
+    #[test]
+    fn still_water_floods_a_dug_hole() {
+        let (physics, mut world) = (physics(), world());
+        fill(&mut world, 0, 8, 1, block::STILL_WATER);
+        fill(&mut world, 8, 16, 1, block::STONE);
+        change(&physics, &mut world, (8, 1, 8), block::AIR);
+        run(&physics, &mut world, 5);
+        assert_eq!(world.get_block(8, 1, 8), Some(block::WATER));
+        assert_eq!(world.get_block(7, 1, 8), Some(block::WATER));
+        assert_eq!(world.get_block(0, 1, 0), Some(block::STILL_WATER));
+    }
+
+    #[test]
+    fn water_and_lava_harden() {
+        let (physics, mut world) = (physics(), world());
+        world.set_block(8, 1, 8, block::STILL_LAVA).unwrap();
+        change(&physics, &mut world, (9, 1, 8), block::WATER);
+        world.set_block(4, 1, 3, block::STILL_WATER).unwrap();
+        change(&physics, &mut world, (3, 1, 3), block::LAVA);
+        run(&physics, &mut world, 1);
+        assert_eq!(world.get_block(8, 1, 8), Some(block::OBSIDIAN));
+        assert_eq!(world.get_block(3, 1, 3), Some(block::STONE));
+    }
+
+    #[test]
+    fn sponge_keeps_water_away_until_removed() {
+        let (physics, mut world) = (physics(), world());
+        fill(&mut world, 0, 16, 1, block::STILL_WATER);
+        change(&physics, &mut world, (8, 1, 8), block::SPONGE);
+        run(&physics, &mut world, 5);
+        assert_eq!(world.get_block(10, 1, 8), Some(block::AIR));
+        assert_eq!(world.get_block(6, 1, 6), Some(block::AIR));
+        assert_eq!(world.get_block(11, 1, 8), Some(block::STILL_WATER));
+
+        change(&physics, &mut world, (8, 1, 8), block::AIR);
+        run(&physics, &mut world, 5);
+        assert_eq!(world.get_block(8, 1, 8), Some(block::WATER));
+        assert_eq!(world.get_block(10, 1, 8), Some(block::WATER));
+    }
+
+    #[test]
+    fn grass_spreads_to_lit_dirt_next_to_it() {
+        let (physics, mut world) = (physics(), world());
+        fill(&mut world, 0, 16, 1, block::DIRT);
+        world.set_block(9, 1, 8, block::GRASS).unwrap();
+        let mut queue = VecDeque::new();
+        for coords in [(8, 1, 8), (2, 1, 2)] {
+            physics.random_tick(&mut world, &BlockRegistry::new(), &mut queue, coords);
+        }
+        assert_eq!(world.get_block(8, 1, 8), Some(block::GRASS));
+        assert_eq!(world.get_block(2, 1, 2), Some(block::DIRT));
+    }
+
+    #[test]
+    fn covered_grass_dies() {
+        let (physics, mut world) = (physics(), world());
+        world.set_block(8, 1, 8, block::GRASS).unwrap();
+        world.set_block(8, 5, 8, block::STONE).unwrap();
+        world.set_block(9, 1, 8, block::GRASS).unwrap();
+        world.set_block(9, 5, 8, block::GLASS).unwrap();
+        let mut queue = VecDeque::new();
+        for coords in [(8, 1, 8), (9, 1, 8)] {
+            physics.random_tick(&mut world, &BlockRegistry::new(), &mut queue, coords);
+        }
+        assert_eq!(world.get_block(8, 1, 8), Some(block::DIRT));
+        assert_eq!(world.get_block(9, 1, 8), Some(block::GRASS));
+    }
+
+    #[test]
+    fn saplings_grow_into_trees() {
+        let (physics, mut world) = (physics(), world());
+        fill(&mut world, 0, 16, 1, block::GRASS);
+        world.set_block(8, 2, 8, block::SAPLING).unwrap();
+        let mut queue = VecDeque::new();
+        physics.random_tick(&mut world, &BlockRegistry::new(), &mut queue, (8, 2, 8));
+        assert_eq!(world.get_block(8, 2, 8), Some(block::LOG));
+        assert_eq!(world.get_block(8, 5, 8), Some(block::LOG));
+        assert!(!queue.is_empty());
+    }
+}
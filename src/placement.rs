@@ -0,0 +1,69 @@
+use crate::block;
+use crate::World;
+
+/// Blocks to set with their coordinates.
+pub type Changes = Vec<((i16, i16, i16), u8)>;
+
+/// Turns a block placed by a player into the changes that really happen, None if it does not apply.
+pub type Rule = fn(&World, (i16, i16, i16), u8) -> Option<Changes>;
+
+/// Rules changing what players place, like slabs stacking into double slabs.
+pub struct PlacementRules {
+    rules: Vec<Rule>,
+}
+
+impl PlacementRules {
+    pub fn new() -> Self {
+        let mut rules = PlacementRules { rules: vec![] };
+        rules.register(stack_slabs);
+        rules
+    }
+
+    pub fn register(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    /// Changes caused by placing a block, the first rule that applies wins.
+    pub fn apply(&self, world: &World, coords: (i16, i16, i16), block_type: u8) -> Changes {
+        self.rules
+            .iter()
+            .find_map(|rule| rule(world, coords, block_type))
+            .unwrap_or_else(|| vec![(coords, block_type)])
+    }
+}
+
+/// A slab placed on a slab joins it into a double slab.
+fn stack_slabs(world: &World, coords: (i16, i16, i16), block_type: u8) -> Option<Changes> {
+    let (x, y, z) = coords;
+    if block_type != block::SLAB || world.get_block(x, y - 1, z) != Some(block::SLAB) {
+        return None;
+    }
+    Some(vec![
+        (coords, block::AIR),
+        ((x, y - 1, z), block::DOUBLE_SLAB),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slabs_stack_into_double_slabs() {
+        let mut world = World::new(16, 16, 16);
+        world.set_block(8, 1, 8, block::SLAB).unwrap();
+        let rules = PlacementRules::new();
+        assert_eq!(
+            rules.apply(&world, (8, 2, 8), block::SLAB),
+            vec![((8, 2, 8), block::AIR), ((8, 1, 8), block::DOUBLE_SLAB)]
+        );
+        assert_eq!(
+            rules.apply(&world, (9, 2, 8), block::SLAB),
+            vec![((9, 2, 8), block::SLAB)]
+        );
+        assert_eq!(
+            rules.apply(&world, (8, 2, 8), block::STONE),
+            vec![((8, 2, 8), block::STONE)]
+        );
+    }
+}
@@ -1,292 +1,473 @@
+use crate::auth::{self, Access};
+use crate::block::{self, BlockRegistry};
+use crate::commands::Caller;
 use crate::config;
 use crate::packets::{self, ClientPacket, ServerPacket};
-use crate::packets::{
-    CLIENT_BLOCK, CS_IDENTIFICATION, CS_MESSAGE, CS_PING_PONG, CS_POSITION_ORIENTATION,
-};
+use crate::placement::PlacementRules;
+use crate::rank::Rank;
 use crate::server;
-use crate::util;
-use std::collections::VecDeque;
-use std::io::{BufReader, BufWriter};
-use std::net::TcpStream;
+use mio::net::TcpStream;
+use mio::Token;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 
 pub struct Player {
     pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub token: Token,
     pub active: bool,
+    inbound: Vec<u8>,           // received bytes not yet decoded into packets
+    pub outbound: VecDeque<u8>, // packets waiting to be written to the socket
 
     pub pid: i8,
     pub name: String,
+    pub world: String, // name of the world the player is in
     position: (i16, i16, i16),
     yaw: u8,
     pitch: u8,
-    operator: u8,
-    authed: bool,
+    pub rank: Rank,
+    pub authed: bool,
+
+    // Classic Protocol Extension
+    cpe: bool,
+    ext_remaining: i16, // ExtEntry packets still expected from the client
+    pub extensions: HashMap<String, i32>, // negotiated extensions with their versions
 }
 
 impl Player {
-    pub fn new(stream: TcpStream, pid: i8) -> Self {
+    pub fn new(stream: TcpStream, addr: SocketAddr, token: Token, pid: i8) -> Self {
         Player {
             stream,
+            addr,
+            token,
             active: true,
+            inbound: Vec::new(),
+            outbound: VecDeque::new(),
             pid,
             name: String::from("Unknown"),
+            world: String::new(),
             position: (0, 0, 0),
             yaw: 0,
             pitch: 0,
-            operator: 0,
+            rank: Rank::Guest,
             authed: false,
+            cpe: false,
+            ext_remaining: 0,
+            extensions: HashMap::new(),
         }
     }
 
-    pub fn tick(
+    pub fn handle_packets(
         &mut self,
-        config: config::Config,
+        config: &config::Config,
+        access: &Access,
+        blocks: &BlockRegistry,
+        placement: &PlacementRules,
         queue: &mut VecDeque<server::Queue>,
         world: &mut crate::World,
     ) -> anyhow::Result<()> {
-        // Data loss if not buffered
-        let mut reader = BufReader::new(self.stream.try_clone()?);
-        let mut writer = BufWriter::new(self.stream.try_clone()?);
+        // Append whatever arrived on the socket to the bytes left over from previous reads
+        self.receive()?;
 
-        // TODO(nv): limit this loop if the sent data is too big -- in future
-        loop {
-            // First try to read first opcode
-            let mut packet_id = None;
-            //match packets::read_byte(&mut self.stream) {
-            match util::read_byte(&mut reader) {
-                Ok(v) => packet_id = Some(v),
-                Err(e) => {
-                    match e.downcast::<std::io::Error>() {
-                        Ok(er) => {
-                            if er.kind() == std::io::ErrorKind::WouldBlock {
-                                // Just return function and don't handle incoming packets to avoid panic
-                                //break;
-                                return Ok(());
-                            }
-                        }
-                        Err(e) => {
-                            panic!(e);
+        // Decode complete packets only, a partial packet stays buffered until the rest arrives
+        while let Some((packet, length)) = packets::decode_packet(&self.inbound)? {
+            self.inbound.drain(..length);
+
+            match packet {
+                ClientPacket::PlayerAuth {
+                    protocol_version,
+                    username,
+                    verification_key,
+                    unused,
+                } => {
+                    if protocol_version != crate::packets::PROTOCOL_VERSION {
+                        let msg = format!(
+                            "Protocol version mismatch! Your: {} - Server: {}",
+                            protocol_version,
+                            packets::PROTOCOL_VERSION
+                        );
+                        self.disconnect(msg)?;
+                        self.active = false;
+                        return Ok(());
+                    }
+
+                    // Set player nickname
+                    self.name.clone_from(&username.trim_end().to_string()); // also trim whitespaces
+                    self.name.shrink_to_fit();
+
+                    // Check the name against the salt shared with the server list, LAN players may skip it.
+                    // Without the heartbeat clients never get the salt, so there is nothing to check against.
+                    if config.server.verify_names
+                        && config.heartbeat.enabled
+                        && !auth::is_bypassed(self.addr.ip(), &config.server.verify_bypass)
+                        && !auth::verify_name(&access.salt, &self.name, &verification_key)
+                    {
+                        println!("{} failed name verification from {}", self.name, self.addr);
+                        self.disconnect(
+                            "Login failed! Close the game and sign in again.".to_string(),
+                        )?;
+                        self.active = false;
+                        return Ok(());
+                    }
+
+                    if let Some(ban) = access.bans.find_name(&self.name) {
+                        println!("Refused banned player {} from {}", self.name, self.addr);
+                        self.disconnect(ban.message())?;
+                        self.active = false;
+                        return Ok(());
+                    }
+
+                    if config.server.whitelist && !access.whitelist.contains(&self.name) {
+                        println!("Refused {} from {}, not whitelisted", self.name, self.addr);
+                        self.disconnect(config.server.whitelist_message.clone())?;
+                        self.active = false;
+                        return Ok(());
+                    }
+
+                    // Client supports protocol extensions, negotiate them before sending the level
+                    if unused == packets::CPE_MAGIC {
+                        self.cpe = true;
+                        packets::ext_info(
+                            &mut self.outbound,
+                            ServerPacket::ExtInfo {
+                                app_name: packets::SOFTWARE_NAME.to_string(),
+                                extension_count: packets::EXTENSIONS.len() as i16,
+                            },
+                        )?;
+                        for (ext_name, version) in packets::EXTENSIONS.iter() {
+                            packets::ext_entry(
+                                &mut self.outbound,
+                                ServerPacket::ExtEntry {
+                                    ext_name: ext_name.to_string(),
+                                    version: *version,
+                                },
+                            )?;
                         }
+                    } else {
+                        self.login(config, access, blocks, queue, world)?;
                     }
                 }
-            }
+                ClientPacket::ExtInfo {
+                    app_name,
+                    extension_count,
+                } => {
+                    println!(
+                        "{} uses {} with {} extensions",
+                        self.name,
+                        app_name.trim_end(),
+                        extension_count
+                    );
 
-            if let Some(packet_id) = packet_id {
-                // TODO(nv): just for debug purpose, 0x08 is sent very often
-                if packet_id != 0x08 {
-                    println!("Received packet_id: {}", packet_id);
-                    println!("");
+                    if !self.cpe || self.authed {
+                        continue;
+                    }
+                    self.ext_remaining = extension_count;
+                    if self.ext_remaining <= 0 {
+                        self.extensions_negotiated(config, access, blocks, queue, world)?;
+                    }
                 }
-                match packet_id {
-                    CS_IDENTIFICATION => {
-                        let data = packets::handle_player_identification(&mut reader)?;
-                        match data {
-                            #[allow(unused_variables)]
-                            ClientPacket::PlayerAuth {
-                                protocol_version,
-                                username,
-                                verification_key,
-                                unused,
-                            } => {
-                                if protocol_version != crate::packets::PROTOCOL_VERSION {
-                                    let msg = format!(
-                                        "Protocol version mismatch! Your: {} - Server: {}",
-                                        protocol_version,
-                                        packets::PROTOCOL_VERSION
-                                    );
-                                    self.disconnect(msg)?;
-                                    return Ok(());
-                                }
-
-                                // Set player nickname
-                                self.name.clone_from(&username.trim_end().to_string()); // also trim whitespaces
-                                self.name.shrink_to_fit();
-
-                                // TODO(nv): authenticate with md5
-
-                                // TODO(nv): set operator type
-                                self.operator = 0x64;
-
-                                // Authed
-                                self.authed = true;
-
-                                // Send server info after successful auth
-                                packets::server_info(
-                                    &mut writer,
-                                    ServerPacket::ServerInfo {
-                                        operator: self.operator,
-                                        name: config.server.name.clone(),
-                                        motd: config.server.motd.clone(),
-                                    },
-                                )?;
-
-                                // Send world information
-                                world.send_world(&mut writer)?;
-
-                                // Spawn authed player in the middle of the world
-                                let mut world_point = world.spawning_point();
-                                world_point.1 += 51;
-                                packets::spawn_player(
-                                    &mut writer,
-                                    ServerPacket::SpawnPlayer {
-                                        pid: -1, // always self
-                                        username: self.name.clone(),
-                                        position: world_point,
-                                        yaw: self.yaw,
-                                        pitch: self.pitch,
-                                    },
-                                )?;
-
-                                // Send to spawn queue for other players
-                                queue.push_back(server::Queue::SpawnPlayer(self.pid));
-                                // also notify of new connection
-                                queue.push_back(server::Queue::ChatMessage(format!(
-                                    "&e{} joined the game",
-                                    self.name.clone()
-                                )));
-                            }
-                            _ => unreachable!(),
-                        }
+                ClientPacket::ExtEntry { ext_name, version } => {
+                    if !self.cpe || self.authed {
+                        continue;
                     }
-                    CS_POSITION_ORIENTATION => {
-                        let data = packets::handle_position_and_orientation(&mut reader)?;
-                        match data {
-                            #[allow(unused_variables)]
-                            ClientPacket::PositionAndOrientation {
-                                pid,
-                                position,
-                                yaw,
-                                pitch,
-                            } => {
-                                self.position = position;
-                                self.yaw = yaw;
-                                self.pitch = pitch;
-                                //println!("Pos: {:?} - Yaw: {} - Pitch: {}", position, yaw, pitch);
-                            }
-                            _ => unreachable!(),
-                        }
+
+                    // Only keep extensions both sides support with the same version
+                    let ext_name = ext_name.trim_end();
+                    if packets::EXTENSIONS.contains(&(ext_name, version)) {
+                        self.extensions.insert(ext_name.to_string(), version);
+                    }
+
+                    self.ext_remaining -= 1;
+                    if self.ext_remaining <= 0 {
+                        println!("{} negotiated extensions: {:?}", self.name, self.extensions);
+                        self.extensions_negotiated(config, access, blocks, queue, world)?;
+                    }
+                }
+                ClientPacket::CustomBlockSupportLevel(level) => {
+                    if !self.cpe || self.authed {
+                        continue;
+                    }
+                    if level < 1 {
+                        self.extensions.remove("CustomBlocks");
+                    }
+                    self.login(config, access, blocks, queue, world)?;
+                }
+                #[allow(unused_variables)]
+                ClientPacket::PositionAndOrientation {
+                    pid,
+                    position,
+                    yaw,
+                    pitch,
+                } => {
+                    self.position = position;
+                    self.yaw = yaw;
+                    self.pitch = pitch;
+                    //println!("Pos: {:?} - Yaw: {} - Pitch: {}", position, yaw, pitch);
+                }
+                ClientPacket::Message(msg) => {
+                    // Commands are run by the server once every player is processed
+                    if let Some(line) = msg.strip_prefix('/') {
+                        println!("{} used: /{}", self.name, line.trim_end());
+                        queue.push_back(server::Queue::Command {
+                            caller: Caller::Player(self.pid),
+                            line: line.trim_end().to_string(),
+                        });
+                        continue;
+                    }
+
+                    // Save it in server's chat to broadcast it later
+                    let mut formatted = format!("{}: ", self.name.clone());
+                    formatted.push_str(&msg);
+                    println!("{}", formatted);
+
+                    // TODO(nv): test = could overflow - not 64 length
+                    // Broadcast message to other players
+                    queue.push_back(server::Queue::ChatMessage(formatted));
+                }
+                ClientPacket::Ping => {} // never returns - just to check if i can write to socket
+                ClientPacket::SetBlock {
+                    coords,
+                    mode,
+                    block_type,
+                } => {
+                    // Players still logging in have no map to change
+                    if !self.authed {
+                        continue;
                     }
-                    CS_MESSAGE => {
-                        let data = packets::handle_player_message(&mut reader)?;
-                        match data {
-                            ClientPacket::Message(msg) => {
-                                // Save it in server's chat to broadcast it later
-                                let mut formatted = format!("{}: ", self.name.clone());
-                                formatted.push_str(&msg);
-                                println!("{}", formatted);
-
-                                // TODO(nv): test = could overflow - not 64 length
-                                // Broadcast message to other players
-                                queue.push_back(server::Queue::ChatMessage(formatted));
-                            }
-                            _ => unreachable!(),
+
+                    // Changes outside of the map have nothing to revert, the client can't show them
+                    let (x, y, z) = coords;
+                    let current = match world.get_block(x, y, z) {
+                        Some(block) => block,
+                        None => {
+                            println!("{} changed a block outside of the map", self.name);
+                            continue;
+                        }
+                    };
+
+                    // Refused changes are reverted on the client with the block the world really has
+                    let perms = access.ranks.perms(self.rank);
+                    let refusal = match mode {
+                        0x0 if !perms.delete => {
+                            Some("&cYou are not allowed to delete blocks!".to_string())
+                        }
+                        0x0 => None,
+                        0x1 if !blocks.can_place(block_type) => {
+                            Some("&cUnknown block!".to_string())
                         }
+                        0x1 if !perms.can_place(block_type) => Some(format!(
+                            "&cYou are not allowed to place {}!",
+                            blocks
+                                .get(block_type)
+                                .map_or("this block", |b| b.name.as_str())
+                        )),
+                        0x1 => None,
+                        _ => Some("&cUnknown block change!".to_string()),
+                    };
+                    if let Some(reason) = refusal {
+                        let current = blocks.for_client(current, self.custom_blocks());
+                        packets::broadcast_block(
+                            &mut self.outbound,
+                            ServerPacket::SetBlock {
+                                coords,
+                                block_type: current,
+                            },
+                        )?;
+                        self.send_message(reason);
+                        continue;
                     }
-                    CS_PING_PONG => println!("Player pong"), // never returns - just to check if i can write to socket
-                    CLIENT_BLOCK => {
-                        let data = packets::handle_set_block(&mut reader)?;
-                        match data {
-                            ClientPacket::SetBlock {
+
+                    // Broadcast the changes to every player, the placer included as rules may alter them
+                    let changes = if mode == 0x0 {
+                        vec![(coords, block::AIR)] // block destroyed
+                    } else {
+                        placement.apply(world, coords, block_type) // else place block which player held
+                    };
+                    for (coords, block_type) in changes {
+                        let (x, y, z) = coords;
+                        let previous = match world.get_block(x, y, z) {
+                            Some(previous) => previous,
+                            None => continue,
+                        };
+                        if world.set_block(x, y, z, block_type).is_ok() {
+                            queue.push_back(server::Queue::SetBlock {
+                                world: self.world.clone(),
                                 coords,
-                                mode,
                                 block_type,
-                            } => {
-                                println!(
-                                    "Coords: {:?} - Mode: {} - BlockType: {}",
-                                    coords, mode, block_type
-                                );
-
-                                // TODO(nv): check if block is valid (loop through all known blocks u8)
-
-                                // TODO(nv): if not valid send to air 0x0, also check world.set_block
-
-                                // Broadcast block to other players
-                                if mode == 0x0 {
-                                    // block destroyed
-                                    queue.push_back(server::Queue::SetBlock {
-                                        coords,
-                                        block_type: 0x00, // air
-                                    });
-
-                                    world.set_block(coords.0, coords.1, coords.2, 0x00);
-                                } else {
-                                    // else place block which player held
-                                    queue.push_back(server::Queue::SetBlock { coords, block_type });
-
-                                    world.set_block(coords.0, coords.1, coords.2, block_type);
-                                }
-                            }
-                            _ => unreachable!(),
+                                previous,
+                            });
                         }
                     }
-                    _ => unreachable!(),
                 }
-            } else {
-                break;
             }
         }
 
         Ok(())
     }
 
-    pub fn spawn_player(&self, player: &Player, world: Option<&mut crate::World>) {
-        // Spawn player for a self.player, if world passed then in the middle of the world
-        let mut data = ServerPacket::SpawnPlayer {
-            pid: player.pid,
-            username: player.name.clone(),
-            position: player.position,
-            yaw: player.yaw,
-            pitch: player.pitch,
-        };
-        if let Some(world) = world {
-            let mut world_center = world.spawning_point();
-            world_center.1 += 51;
-            if let ServerPacket::SpawnPlayer {
-                ref mut position, ..
-            } = data
-            {
-                *position = world_center;
+    /// Reads everything available on the socket into the inbound buffer without blocking.
+    fn receive(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    // Connection closed by the client
+                    self.active = false;
+                    break Ok(());
+                }
+                Ok(count) => {
+                    self.inbound.extend_from_slice(&buf[..count]);
+                    if self.inbound.len() > packets::MAX_INBOUND_BYTES {
+                        break Err(packets::PacketError::Oversize(self.inbound.len()).into());
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e.into()),
             }
         }
-        let mut writer = BufWriter::new(&self.stream);
-        match packets::spawn_player(&mut writer, data) {
-            Ok(_) => {}
-            Err(_) => {}
-        };
     }
 
-    pub fn broadcast_position(&self, player: &Player) {
-        let mut writer = BufWriter::new(&self.stream);
-        match packets::player_position_update(
-            &mut writer,
-            ServerPacket::PositionAndOrientation {
-                pid: player.pid,
-                position: player.position,
-                yaw: player.yaw,
-                pitch: player.pitch,
+    /// Writes as much of the outbound queue as the socket accepts without blocking.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        while !self.outbound.is_empty() {
+            let (data, _) = self.outbound.as_slices();
+            match self.stream.write(data) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+                Ok(count) => {
+                    self.outbound.drain(..count);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks clients with CustomBlocks for their support level first, the others log in right away.
+    fn extensions_negotiated(
+        &mut self,
+        config: &config::Config,
+        access: &Access,
+        blocks: &BlockRegistry,
+        queue: &mut VecDeque<server::Queue>,
+        world: &mut crate::World,
+    ) -> anyhow::Result<()> {
+        if self.custom_blocks() {
+            packets::custom_block_support_level(
+                &mut self.outbound,
+                ServerPacket::CustomBlockSupportLevel(packets::CUSTOM_BLOCKS_LEVEL),
+            )
+        } else {
+            self.login(config, access, blocks, queue, world)
+        }
+    }
+
+    fn login(
+        &mut self,
+        config: &config::Config,
+        access: &Access,
+        blocks: &BlockRegistry,
+        queue: &mut VecDeque<server::Queue>,
+        world: &mut crate::World,
+    ) -> anyhow::Result<()> {
+        self.rank = access.ranks.rank_of(&self.name);
+
+        // Authed
+        self.authed = true;
+
+        // Send server info after successful auth
+        packets::server_info(
+            &mut self.outbound,
+            ServerPacket::ServerInfo {
+                operator: self.rank.user_type(),
+                name: config.server.name.clone(),
+                motd: config.server.motd.clone(),
             },
-        ) {
-            Ok(_) => {}
-            Err(_) => {}
-        };
+        )?;
+
+        self.enter_world(world, blocks)?;
+
+        // Send to spawn queue for other players
+        queue.push_back(server::Queue::SpawnPlayer(self.pid));
+        // also notify of new connection
+        queue.push_back(server::Queue::ChatMessage(format!(
+            "&e{} joined the game",
+            self.name.clone()
+        )));
+
+        Ok(())
     }
 
-    pub fn check_liveness(&mut self) {
-        match packets::ping(&mut self.stream) {
-            Ok(_) => {}
-            Err(e) => match e.downcast::<std::io::Error>() {
-                Ok(err) => {
-                    // Set inactive and delete it in next tick iteration
-                    if err.kind() != std::io::ErrorKind::WouldBlock {
-                        self.active = false;
-                    }
-                }
-                Err(pe) => panic!(pe),
+    /// Sends the level and spawns the player at its spawning point, others are told by the queue.
+    pub fn enter_world(
+        &mut self,
+        world: &mut crate::World,
+        blocks: &BlockRegistry,
+    ) -> anyhow::Result<()> {
+        self.world.clone_from(&world.name);
+        let custom_blocks = self.custom_blocks();
+        world.send_world(&mut self.outbound, blocks, custom_blocks)?;
+
+        // Spawn in the middle of the world
+        let mut world_point = world.spawning_point();
+        world_point.1 += 51;
+        self.position = world_point;
+        self.yaw = world.meta.spawn_yaw;
+        self.pitch = world.meta.spawn_pitch;
+        packets::spawn_player(
+            &mut self.outbound,
+            ServerPacket::SpawnPlayer {
+                pid: -1, // always self
+                username: self.name.clone(),
+                position: world_point,
+                yaw: self.yaw,
+                pitch: self.pitch,
             },
+        )?;
+        Ok(())
+    }
+
+    pub fn spawn_packet(&self, world: Option<&mut crate::World>) -> ServerPacket<'static> {
+        // Spawn packet of this player, if world passed then in the middle of the world
+        let mut position = self.position;
+        if let Some(world) = world {
+            position = world.spawning_point();
+            position.1 += 51;
         }
+        ServerPacket::SpawnPlayer {
+            pid: self.pid,
+            username: self.name.clone(),
+            position,
+            yaw: self.yaw,
+            pitch: self.pitch,
+        }
+    }
+
+    pub fn position_packet(&self) -> ServerPacket<'static> {
+        ServerPacket::PositionAndOrientation {
+            pid: self.pid,
+            position: self.position,
+            yaw: self.yaw,
+            pitch: self.pitch,
+        }
+    }
+
+    /// Client knows the blocks 50 to 65, the others are sent their fallbacks.
+    pub fn custom_blocks(&self) -> bool {
+        self.extensions.contains_key("CustomBlocks")
+    }
+
+    pub fn send_message(&mut self, msg: String) {
+        let _ = packets::broadcast_message(&mut self.outbound, ServerPacket::Message(msg));
     }
 
     pub fn disconnect(&mut self, reason: String) -> anyhow::Result<()> {
-        packets::kick(&mut self.stream, ServerPacket::Kick(reason))?;
+        packets::kick(&mut self.outbound, ServerPacket::Kick(reason))?;
+        // Best effort, the player is dropped right after
+        self.flush()?;
         Ok(())
     }
 }
@@ -0,0 +1,170 @@
+use crate::block;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const RANKS_PATH: &str = "ranks.yaml";
+
+static NO_PERMS: RankPerms = RankPerms {
+    build: false,
+    delete: false,
+    commands: false,
+    disallowed_blocks: Vec::new(),
+};
+
+/// Ranks of players, ordered from the least to the most privileged.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    Guest,
+    Builder,
+    Op,
+    Owner,
+}
+
+impl Rank {
+    /// User type byte sent to the client, operators may break bedrock.
+    pub fn user_type(self) -> u8 {
+        if self >= Rank::Op {
+            0x64
+        } else {
+            0x00
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rank::Guest => "guest",
+            Rank::Builder => "builder",
+            Rank::Op => "op",
+            Rank::Owner => "owner",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rank> {
+        [Rank::Guest, Rank::Builder, Rank::Op, Rank::Owner]
+            .iter()
+            .find(|r| r.name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RankPerms {
+    pub build: bool,
+    pub delete: bool,
+    pub commands: bool,
+    pub disallowed_blocks: Vec<u8>, // block ids this rank may not place
+}
+
+impl RankPerms {
+    pub fn can_place(&self, block_type: u8) -> bool {
+        self.build && !self.disallowed_blocks.contains(&block_type)
+    }
+}
+
+/// Ranks of every known player and what each rank may do, stored in `ranks.yaml`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ranks {
+    pub default: Rank, // rank of players not listed
+    pub perms: BTreeMap<Rank, RankPerms>,
+    pub players: BTreeMap<String, Rank>,
+}
+
+impl Default for Ranks {
+    fn default() -> Self {
+        let mut perms = BTreeMap::new();
+        perms.insert(
+            Rank::Guest,
+            RankPerms {
+                build: true,
+                delete: true,
+                commands: true,
+                disallowed_blocks: vec![
+                    block::BEDROCK,
+                    block::WATER,
+                    block::STILL_WATER,
+                    block::LAVA,
+                    block::STILL_LAVA,
+                ],
+            },
+        );
+        perms.insert(
+            Rank::Builder,
+            RankPerms {
+                build: true,
+                delete: true,
+                commands: true,
+                disallowed_blocks: vec![block::BEDROCK],
+            },
+        );
+        for rank in [Rank::Op, Rank::Owner].iter() {
+            perms.insert(
+                *rank,
+                RankPerms {
+                    build: true,
+                    delete: true,
+                    commands: true,
+                    disallowed_blocks: vec![],
+                },
+            );
+        }
+
+        Ranks {
+            default: Rank::Guest,
+            perms,
+            players: BTreeMap::new(),
+        }
+    }
+}
+
+impl Ranks {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut ranks = Ranks::default();
+        match std::fs::read_to_string(RANKS_PATH) {
+            Ok(read_str) => {
+                ranks = serde_yaml::from_str(&read_str)?;
+            }
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    ranks.save()?;
+                } else {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(ranks)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let wr_str = serde_yaml::to_string(self)?;
+        std::fs::write(RANKS_PATH, wr_str)?;
+        Ok(())
+    }
+
+    pub fn rank_of(&self, name: &str) -> Rank {
+        match self
+            .players
+            .iter()
+            .find(|(player, _)| player.eq_ignore_ascii_case(name))
+        {
+            Some((_, rank)) => *rank,
+            None => self.default,
+        }
+    }
+
+    /// Changes the rank of a player and writes the file.
+    pub fn set_rank(&mut self, name: &str, rank: Rank) -> anyhow::Result<()> {
+        self.players
+            .retain(|player, _| !player.eq_ignore_ascii_case(name));
+        if rank != self.default {
+            self.players.insert(name.to_string(), rank);
+        }
+        self.save()
+    }
+
+    /// Permissions of a rank, a rank missing from the file may do nothing.
+    pub fn perms(&self, rank: Rank) -> &RankPerms {
+        self.perms.get(&rank).unwrap_or(&NO_PERMS)
+    }
+}
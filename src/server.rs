@@ -1,65 +1,173 @@
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token, Waker};
 use std::collections::VecDeque;
-use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::auth::Access;
+use crate::block::BlockRegistry;
+use crate::commands::{Caller, Commands};
 use crate::config::{Config, WorldGenCfg};
+use crate::console;
+use crate::generator::{self, FlatGenerator};
+use crate::heartbeat::Heartbeat;
 use crate::packets;
+use crate::physics::Physics;
+use crate::placement::PlacementRules;
+use crate::rank::Rank;
+use crate::worlds::Worlds;
+use crate::Clock;
 use crate::Player;
 use crate::World;
 
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+// Player tokens start after the reserved ones and are never reused
+const FIRST_PLAYER_TOKEN: usize = 2;
+// Pings are only a keep-alive now, closed sockets are reported by poll
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+
 pub enum Queue {
     SpawnPlayer(i8),
-    DespawnPlayer(i8),
+    DespawnPlayer {
+        pid: i8,
+        world: String, // the player may be gone already
+    },
     ChatMessage(String),
+    Command {
+        caller: Caller,
+        line: String, // without the leading slash
+    },
     SetBlock {
+        world: String,
         coords: (i16, i16, i16),
         block_type: u8,
+        previous: u8, // block it replaced
     },
 }
 
 pub struct Server {
-    config: Config,
+    pub config: Config,
+    pub clock: Clock,
+    pub stopping: bool, // set to shut the server down
 
     // server specific
+    poll: Poll,
+    events: Events,
     listener: TcpListener,
+    pub waker: Arc<Waker>, // interrupts a blocking poll from other threads
+    next_token: usize,
+    last_ping: Instant,
     max_players: i8,
+    console: Receiver<String>, // lines typed in the server console
+    pub access: Access,
+    pub blocks: BlockRegistry,
+    pub physics: Physics,
+    pub placement: PlacementRules,
+    users: Arc<AtomicUsize>, // players count shared with the heartbeat
 
     // events(values) that must be processed later after every player ticked
     pub queue: VecDeque<Queue>,
+    pub commands: Commands,
 
     // game specific
     pub players: Vec<Player>,
-    pub world: World,
+    pub worlds: Worlds,
 }
 
 impl Server {
     pub fn new(config: Config) -> anyhow::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
         let address = config.server.ip;
-        let listener = TcpListener::bind(address)?;
-        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::bind(address.into())?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        let blocks = BlockRegistry::new();
 
         // Tested for now 1024x32x1024
-        let world: World;
-        match config.world.gen {
-            WorldGenCfg::FromFile(ref path) => {
-                world = World::load_world(path)?;
-            }
+        let world = match config.world.gen {
+            WorldGenCfg::FromFile(ref path) => World::load_world(path, &blocks)?,
             WorldGenCfg::FlatMap {
                 width,
                 height,
                 length,
+                ref layers,
+                sea_level,
+                bedrock,
+            } => {
+                if let Some(layer) = layers.iter().find(|l| !blocks.is_valid(l.block)) {
+                    return Err(anyhow::anyhow!(
+                        "Unknown block {} in the flat map layers",
+                        layer.block
+                    ));
+                }
+                let generator = FlatGenerator {
+                    layers: layers.clone(),
+                    sea_level,
+                    bedrock,
+                };
+                World::generate(width, height, length, &generator)
+            }
+            WorldGenCfg::Generated {
+                generator,
+                width,
+                height,
+                length,
+                seed,
             } => {
-                world = World::new(width, height, length);
+                let seed = seed.unwrap_or_else(rand::random);
+                println!("Generating {:?} world with seed {}", generator, seed);
+                World::generate(
+                    width,
+                    height,
+                    length,
+                    generator::from_kind(generator, seed).as_ref(),
+                )
             }
+        };
+
+        let worlds = Worlds::new(world, &config.world.path, config.world.unload_after)?;
+
+        // Announce the server to the server list
+        let access = Access::new()?;
+        let users = Arc::new(AtomicUsize::new(0));
+        if config.heartbeat.enabled {
+            Heartbeat::new(&config, access.salt.clone(), users.clone()).start()?;
+        } else if config.server.verify_names {
+            // Clients only learn the salt from the server list
+            println!("Warning: the heartbeat is off, names are not verified");
         }
 
+        let console = console::start(waker.clone())?;
+
         let max_players = config.server.max_players;
+        let physics = Physics::new(&config.simulation);
         Ok(Server {
+            clock: Clock::new(config.simulation.server_tick_rate as u128),
+            stopping: false,
             config,
+            poll,
+            events: Events::with_capacity(256),
             listener,
+            waker,
+            next_token: FIRST_PLAYER_TOKEN,
+            last_ping: Instant::now(),
             max_players,
+            console,
+            access,
+            blocks,
+            physics,
+            placement: PlacementRules::new(),
+            users,
             queue: VecDeque::new(),
+            commands: Commands::new(),
             players: vec![],
-            world,
+            worlds,
         })
     }
 
@@ -79,157 +187,392 @@ impl Server {
         None
     }
 
-    pub fn tick(&mut self) -> anyhow::Result<()> {
-        // Accept new connections
-        for inc in self.listener.incoming() {
-            let _ = match inc {
-                Ok(stream) => {
-                    let mut player = Player::new(stream, -1);
-
-                    // Players count
-                    let current_players = self.players.len() as i8;
-                    if current_players + 1 > self.max_players {
-                        match player.disconnect("Server is full!".to_string()) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
-                    }
+    /// Waits up to `timeout` for network events and handles them as they come,
+    /// a `None` timeout blocks until something happens.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        if let Err(e) = self.poll.poll(&mut self.events, timeout) {
+            // Interrupted by a signal, e.g. Ctrl-C
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e.into());
+        }
 
-                    // Gen pid then add incoming player to players list
-                    if let Some(pid) = self.gen_pid() {
-                        player.pid = pid;
-                        self.players.push(player);
-                    } else {
-                        // Server is full kick! max pid
-                        match player.disconnect("Server is full!".to_string()) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
-                    }
+        let mut incoming = false;
+        let mut tokens = Vec::new();
+        for event in self.events.iter() {
+            match event.token() {
+                LISTENER => incoming = true,
+                WAKER => {}
+                token => tokens.push((
+                    token,
+                    event.is_readable(),
+                    event.is_writable(),
+                    event.is_error(),
+                )),
+            }
+        }
+
+        if incoming {
+            self.accept()?;
+        }
+
+        for (token, readable, writable, error) in tokens {
+            let player = match self.players.iter_mut().find(|p| p.token == token) {
+                Some(player) => player,
+                None => continue,
+            };
+            let world = match self.worlds.get_mut(&player.world) {
+                Some(world) => world,
+                None => continue,
+            };
+            if error {
+                player.active = false;
+                continue;
+            }
+            if writable {
+                if let Err(e) = player.flush() {
+                    println!("Player: {} - Err: {}", player.pid, e);
+                    player.active = false;
+                    continue;
                 }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        break; // just to out of blocking-forloop to process ticking server
-                    } else {
-                        panic!(e);
+            }
+            if readable {
+                // Hang-ups are noticed when reading returns no data
+                match player.handle_packets(
+                    &self.config,
+                    &self.access,
+                    &self.blocks,
+                    &self.placement,
+                    &mut self.queue,
+                    world,
+                ) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        // Kick clients breaking the protocol with the reason, the others are just dropped
+                        match e.downcast_ref::<packets::PacketError>() {
+                            Some(err) => {
+                                println!("Kicking player: {} - {}", player.pid, err);
+                                let _ = player.disconnect(err.to_string());
+                            }
+                            None => println!("Player: {} - Err: {}", player.pid, e),
+                        }
+                        // Mark it inactive to delete it below
+                        player.active = false;
                     }
                 }
+            }
+        }
+
+        // Console commands are run like the players' ones
+        while let Ok(line) = self.console.try_recv() {
+            let line = line.trim();
+            self.queue.push_back(Queue::Command {
+                caller: Caller::Console,
+                line: line.strip_prefix('/').unwrap_or(line).to_string(),
+            });
+        }
+
+        self.remove_inactive();
+        self.process_queue();
+        self.flush_players();
+
+        Ok(())
+    }
+
+    fn accept(&mut self) -> anyhow::Result<()> {
+        loop {
+            let (mut stream, addr) = match self.listener.accept() {
+                Ok(inc) => inc,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
             };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            let mut player = Player::new(stream, addr, token, -1);
+            player.world.clone_from(&self.worlds.main);
+
+            // Players count
+            let current_players = self.players.len() as i8;
+            if let Some(ban) = self.access.bans.find_ip(addr.ip()) {
+                println!("Refused banned address {}", addr);
+                let _ = player.disconnect(ban.message());
+            } else if current_players + 1 > self.max_players {
+                let _ = player.disconnect("Server is full!".to_string());
+            } else if let Some(pid) = self.gen_pid() {
+                // Gen pid then add incoming player to players list
+                player.pid = pid;
+                self.players.push(player);
+            } else {
+                // Server is full kick! max pid
+                let _ = player.disconnect("Server is full!".to_string());
+            }
         }
+    }
 
-        // Delete inactive players -- lost connection
-        self.players.retain(|p| p.active == true);
+    pub fn tick(&mut self) -> anyhow::Result<()> {
+        // Progress the physics of every world, changed blocks are broadcast with the queue
+        for world in self.worlds.iter_mut() {
+            self.physics.tick(world, &self.blocks, &mut self.queue);
+        }
 
-        // TODO(nv): Progress world & physics
-        //self.world.tick()?;
+        // Writing to a half-open connection fails on flush
+        if self.last_ping.elapsed() >= PING_INTERVAL {
+            self.last_ping = Instant::now();
+            for player in self.players.iter_mut() {
+                let _ = packets::ping(&mut player.outbound);
+            }
+        }
 
-        // Progress players
-        for player in self.players.iter_mut() {
-            // Try to send ping and determine if the socket is still open
-            // Basicaly the idea is simple. Every write or read on socket may fail. But with every tick
-            // TcpStream will be checked for closed socket and mark it inactive to delete player later and not to process him.
-            player.check_liveness();
-
-            // Tick player if he is alive
-            if player.active {
-                match player.tick(self.config.clone(), &mut self.queue, &mut self.world) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("Player: {} - Err: {}", player.pid, e);
-                        // Mark it inactive and continue
-                        player.active = false; // Almost never happens? At least here
-                        continue;
-                    }
+        // Broadcast player positions to the players in the same world, once they are logged in
+        let positions = self
+            .players
+            .iter()
+            .filter(|p| p.authed)
+            .map(|p| (p.pid, p.world.clone(), p.position_packet()))
+            .collect::<Vec<_>>();
+        for o_player in self.players.iter_mut().filter(|p| p.authed) {
+            for (pid, world, position) in positions.iter() {
+                if o_player.pid == *pid || o_player.world != *world {
+                    continue;
                 }
-            } else {
-                // If not active then despawn for other players
-                self.queue.push_back(Queue::DespawnPlayer(player.pid));
-                self.queue.push_back(Queue::ChatMessage(format!(
-                    "&e{} left the game",
-                    player.name.clone()
-                )));
+                let _ = packets::player_position_update(&mut o_player.outbound, position.clone());
             }
         }
 
-        println!("Queue to process: {}", self.queue.len());
+        self.process_queue();
+        self.flush_players();
+
+        Ok(())
+    }
+
+    /// Saves and unloads the worlds left without players for a while.
+    pub fn unload_idle_worlds(&mut self) {
+        let occupied = self
+            .players
+            .iter()
+            .map(|p| p.world.clone())
+            .collect::<Vec<_>>();
+        self.worlds.unload_idle(&occupied);
+    }
+
+    fn remove_inactive(&mut self) {
+        // Delete inactive players -- lost connection or kicked, and despawn them for others
+        for player in self.players.iter().filter(|p| !p.active && p.authed) {
+            self.queue.push_back(Queue::DespawnPlayer {
+                pid: player.pid,
+                world: player.world.clone(),
+            });
+            self.queue.push_back(Queue::ChatMessage(format!(
+                "&e{} left the game",
+                player.name.clone()
+            )));
+        }
+        self.players.retain(|p| p.active);
+
+        let users = self.players.iter().filter(|p| p.authed).count();
+        self.users.store(users, Ordering::Relaxed);
+    }
+
+    fn process_queue(&mut self) {
         // Process events queue
-        while let Some(ev_queue) = self.queue.pop_back() {
+        while let Some(ev_queue) = self.queue.pop_front() {
             match ev_queue {
                 // Player spawner
-                // If a new player connects send it to others and also send current players to him
+                // If a player enters a world send it to others there and also send them to him
                 Queue::SpawnPlayer(pid) => {
                     if let Some(inc_player) = self.players.iter().find(|c| c.pid == pid) {
-                        for player in self.players.iter() {
-                            if player.pid == pid {
-                                continue;
-                            }
+                        let world = inc_player.world.clone();
+                        // Spawn in the middle
+                        let inc_spawn = match self.worlds.get_mut(&world) {
+                            Some(w) => inc_player.spawn_packet(Some(w)),
+                            None => inc_player.spawn_packet(None),
+                        };
 
-                            // Spawn in the middle
-                            player.spawn_player(inc_player, Some(&mut self.world));
+                        // Spawn for a new player other already existing players
+                        let spawns = self
+                            .players
+                            .iter()
+                            .filter(|p| p.pid != pid && p.authed && p.world == world)
+                            .map(|p| p.spawn_packet(None))
+                            .collect::<Vec<_>>();
 
-                            // Spawn for a new player other already existing players
-                            inc_player.spawn_player(player, None);
+                        for player in self.players.iter_mut() {
+                            if !player.authed || player.world != world {
+                                continue;
+                            }
+                            if player.pid == pid {
+                                for spawn in spawns.iter() {
+                                    let _ =
+                                        packets::spawn_player(&mut player.outbound, spawn.clone());
+                                }
+                            } else {
+                                let _ =
+                                    packets::spawn_player(&mut player.outbound, inc_spawn.clone());
+                            }
                         }
                     }
                 }
-                Queue::DespawnPlayer(pid) => {
-                    // Despawn inactive player for others
-                    for player in self.players.iter_mut() {
-                        match packets::despawn_player(
-                            &mut player.stream,
+                Queue::DespawnPlayer { pid, world } => {
+                    // Despawn inactive player for others in its world
+                    for player in self
+                        .players
+                        .iter_mut()
+                        .filter(|p| p.authed && p.world == world)
+                    {
+                        let _ = packets::despawn_player(
+                            &mut player.outbound,
                             packets::ServerPacket::DespawnPlayer(pid),
-                        ) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
+                        );
                     }
                 }
                 Queue::ChatMessage(msg) => {
-                    for player in self.players.iter_mut() {
+                    for player in self.players.iter_mut().filter(|p| p.authed) {
                         // Yeah I know... but ¯\_(ツ)_/¯
-                        match packets::broadcast_message(
-                            &mut player.stream,
+                        let _ = packets::broadcast_message(
+                            &mut player.outbound,
                             packets::ServerPacket::Message(msg.clone()),
-                        ) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
+                        );
                     }
                 }
-                Queue::SetBlock { coords, block_type } => {
-                    for player in self.players.iter_mut() {
-                        match packets::broadcast_block(
-                            &mut player.stream,
+                Queue::Command { caller, line } => self.run_command(caller, &line),
+                Queue::SetBlock {
+                    world,
+                    coords,
+                    block_type,
+                    previous,
+                } => {
+                    // Every change may wake up the physics of the blocks around it
+                    if let Some(w) = self.worlds.get_mut(&world) {
+                        self.physics
+                            .block_changed(w, &self.blocks, coords, previous);
+                    }
+
+                    // Players still logging in get the change with the level
+                    for player in self
+                        .players
+                        .iter_mut()
+                        .filter(|p| p.authed && p.world == world)
+                    {
+                        let block_type = self.blocks.for_client(block_type, player.custom_blocks());
+                        let _ = packets::broadcast_block(
+                            &mut player.outbound,
                             packets::ServerPacket::SetBlock { coords, block_type },
-                        ) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
+                        );
                     }
                 }
             }
         }
+    }
 
-        // Broadcast player positions
-        for o_player in self.players.iter() {
-            for r_player in self.players.iter() {
-                if o_player.pid == r_player.pid {
-                    continue;
+    fn run_command(&mut self, caller: Caller, line: &str) {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        let name = match args.first() {
+            Some(name) => *name,
+            None => return,
+        };
+
+        // Copy what's needed out of the registry, handlers borrow the whole server
+        let (rank, handler) = match self.commands.find(name) {
+            Some(command) => (command.rank, command.handler),
+            None => {
+                self.message(caller, format!("&cUnknown command: {}", name));
+                return;
+            }
+        };
+        if let Caller::Player(_) = caller {
+            let perms = self.access.ranks.perms(self.caller_rank(caller));
+            if !perms.commands {
+                self.message(caller, "&cYou are not allowed to use commands!".to_string());
+                return;
+            }
+        }
+        if self.caller_rank(caller) < rank {
+            self.message(
+                caller,
+                "&cYou are not allowed to use this command!".to_string(),
+            );
+            return;
+        }
+
+        if let Err(e) = handler(self, caller, &args[1..]) {
+            self.message(caller, format!("&c{}", e));
+        }
+    }
+
+    /// Sends a chat message to a single player, or prints it for the console.
+    pub fn message(&mut self, caller: Caller, msg: String) {
+        match caller {
+            Caller::Player(pid) => {
+                if let Some(player) = self.players.iter_mut().find(|p| p.pid == pid) {
+                    player.send_message(msg);
                 }
-                o_player.broadcast_position(r_player);
             }
+            Caller::Console => println!("{}", console::strip_colors(&msg)),
         }
+    }
 
-        Ok(())
+    pub fn caller_rank(&self, caller: Caller) -> Rank {
+        match caller {
+            Caller::Player(pid) => match self.players.iter().find(|p| p.pid == pid) {
+                Some(player) => player.rank,
+                None => Rank::Guest,
+            },
+            Caller::Console => Rank::Owner,
+        }
+    }
+
+    /// The console outranks everyone, players only those of a lower rank.
+    pub fn outranks(&self, caller: Caller, rank: Rank) -> bool {
+        caller == Caller::Console || self.caller_rank(caller) > rank
+    }
+
+    pub fn caller_name(&self, caller: Caller) -> String {
+        match caller {
+            Caller::Player(pid) => match self.players.iter().find(|p| p.pid == pid) {
+                Some(player) => player.name.clone(),
+                None => String::from("Unknown"),
+            },
+            Caller::Console => String::from("Console"),
+        }
+    }
+
+    /// Finds an online player by name, ignoring case.
+    pub fn find_player(&mut self, name: &str) -> Option<&mut Player> {
+        self.players
+            .iter_mut()
+            .find(|p| p.authed && p.name.eq_ignore_ascii_case(name))
+    }
+
+    fn flush_players(&mut self) {
+        // Send queued packets, players not reading them fast enough are kicked
+        let high_water = self.config.server.outbound_high_water;
+        for player in self.players.iter_mut() {
+            if let Err(e) = player.flush() {
+                println!("Player: {} - Err: {}", player.pid, e);
+                player.active = false;
+            } else if player.outbound.len() > high_water {
+                println!(
+                    "Kicking player: {} - {} bytes waiting to be sent",
+                    player.pid,
+                    player.outbound.len()
+                );
+                let _ = player.disconnect("Connection too slow!".to_string());
+                player.active = false;
+            }
+        }
     }
 
     pub fn kick_players(&mut self) {
         for player in self.players.iter_mut() {
             // Stream may be closed already, only not to panic
-            match player.disconnect("Server closed!".to_string()) {
-                Ok(_) => {}
-                Err(_) => {}
-            }
+            let _ = player.disconnect("Server closed!".to_string());
         }
     }
 }
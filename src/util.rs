@@ -61,44 +61,52 @@ pub fn read_utfstring<R: Read>(reader: &mut R) -> anyhow::Result<String> {
     }
 }
 
+/// Reads `count` bytes, the buffer only grows with the bytes actually read.
 pub fn read_bytearray<R: Read>(reader: &mut R, count: usize) -> anyhow::Result<Vec<i8>> {
-    let mut buf = vec![0u8; count];
-    reader.read_exact(&mut buf)?;
+    let mut buf = Vec::new();
+    reader.take(count as u64).read_to_end(&mut buf)?;
+    if buf.len() != count {
+        return Err(anyhow::anyhow!(
+            "Expected {} bytes, got {}",
+            count,
+            buf.len()
+        ));
+    }
     Ok(buf.iter().map(|x| *x as i8).collect())
 }
 
 pub fn write_byte<W: Write>(writer: &mut W, val: u8) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
 pub fn write_sbyte<W: Write>(writer: &mut W, val: i8) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
 pub fn write_short<W: Write>(writer: &mut W, val: i16) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
 pub fn write_int<W: Write>(writer: &mut W, val: i32) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
 pub fn write_long<W: Write>(writer: &mut W, val: i64) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
 pub fn write_float<W: Write>(writer: &mut W, val: f32) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
 pub fn write_double<W: Write>(writer: &mut W, val: f64) -> anyhow::Result<()> {
-    writer.write(&val.to_be_bytes())?;
+    writer.write_all(&val.to_be_bytes())?;
     Ok(())
 }
 
@@ -109,9 +117,9 @@ pub fn write_mcstring<W: Write>(writer: &mut W, val: String) -> anyhow::Result<(
     if vb_len > buf.len() {
         buf.clone_from_slice(&val_bytes[..64]);
     } else {
-        buf[..vb_len].clone_from_slice(&val_bytes);
+        buf[..vb_len].clone_from_slice(val_bytes);
     }
-    writer.write(&buf)?;
+    writer.write_all(&buf)?;
     Ok(())
 }
 
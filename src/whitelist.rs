@@ -0,0 +1,71 @@
+const WHITELIST_PATH: &str = "whitelist.txt";
+
+/// Names allowed to join when the whitelist is on, stored one per line in `whitelist.txt`.
+pub struct Whitelist {
+    names: Vec<String>,
+}
+
+impl Whitelist {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut whitelist = Whitelist { names: vec![] };
+        match std::fs::read_to_string(WHITELIST_PATH) {
+            Ok(read_str) => {
+                // Blank lines and lines starting with # are ignored
+                whitelist.names = read_str
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from)
+                    .collect();
+            }
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    whitelist.save()?;
+                } else {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(whitelist)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut wr_str = String::from("# One player name per line\n");
+        for name in self.names.iter() {
+            wr_str.push_str(name);
+            wr_str.push('\n');
+        }
+        std::fs::write(WHITELIST_PATH, wr_str)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Adds a name and writes the file, returns false if it was listed already.
+    pub fn add(&mut self, name: &str) -> anyhow::Result<bool> {
+        if self.contains(name) {
+            return Ok(false);
+        }
+        self.names.push(name.to_string());
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Removes a name and writes the file, returns false if it was not listed.
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<bool> {
+        let count = self.names.len();
+        self.names.retain(|n| !n.eq_ignore_ascii_case(name));
+        if self.names.len() == count {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
@@ -1,85 +1,151 @@
+use crate::block::{self, BlockRegistry};
+use crate::classic_world::ClassicWorldMeta;
+use crate::generator::WorldGenerator;
+use crate::java_level;
+use crate::lvl;
 use crate::nbt::{self, NBT};
 use crate::packets::{self, ServerPacket};
 use flate2::{bufread, write};
-use std::collections::HashMap;
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
 
 pub struct World {
+    pub name: String, // set by the world manager
     pub width: i16,
     pub height: i16,
     pub length: i16,
     pub blocks: Vec<u8>,
 
     spawn: (i16, i16, i16),
+    pub meta: ClassicWorldMeta, // the other tags of the .cw file
+
+    ticks: u64,                                // physics ticks run so far
+    pending: BTreeSet<(u64, (i16, i16, i16))>, // block updates with the tick they are due
 }
 
 impl World {
+    /// Empty world filled with air.
     pub fn new(width: i16, height: i16, length: i16) -> Self {
         let count = width as usize * height as usize * length as usize;
-        let blocks = vec![0x00u8; count]; // fill with air
-        let mut world = World {
+        let blocks = vec![block::AIR; count];
+        World {
+            name: String::new(),
             width,
             height,
             length,
             blocks,
             spawn: (width / 2, height / 2, length / 2),
-        };
+            meta: ClassicWorldMeta::default(),
+            ticks: 0,
+            pending: BTreeSet::new(),
+        }
+    }
 
-        // TODO(nv): make builder pattern
-        world.generate_flat_map();
+    /// Runs the generator on an empty world and spawns players on top of the middle column.
+    pub fn generate(width: i16, height: i16, length: i16, generator: &dyn WorldGenerator) -> Self {
+        let mut world = World::new(width, height, length);
+        generator.generate(&mut world);
+        world.meta = ClassicWorldMeta::generated(generator.name());
+
+        let (x, z) = (width / 2, length / 2);
+        let ground = (0..height)
+            .rev()
+            .find(|&y| world.get_block(x, y, z).is_some_and(|b| b != block::AIR));
+        if let Some(y) = ground {
+            world.spawn = (x, (y + 1).min(height - 1), z);
+        }
 
         world
     }
 
-    fn generate_flat_map(&mut self) {
-        // Basic algorithm
-        for y in 0..self.height / 2 {
-            for x in 0..self.width {
-                for z in 0..self.length {
-                    let idx = self.coord_to_block_idx(x, y, z);
-                    if y < (self.height / 2 - 1) {
-                        self.blocks[idx] = 0x03;
-                    } else {
-                        self.blocks[idx] = 0x02;
-                    }
-                }
-            }
-        }
+    pub fn in_bounds(&self, x: i16, y: i16, z: i16) -> bool {
+        (0..self.width).contains(&x)
+            && (0..self.height).contains(&y)
+            && (0..self.length).contains(&z)
     }
 
-    fn coord_to_block_idx(&mut self, x: i16, y: i16, z: i16) -> usize {
+    /// Index of the block in `blocks`, None outside of the map.
+    fn coord_to_block_idx(&self, x: i16, y: i16, z: i16) -> Option<usize> {
+        if !self.in_bounds(x, y, z) {
+            return None;
+        }
         let x = x as usize;
         let y = y as usize;
         let z = z as usize;
         let width = self.width as usize;
         let length = self.length as usize;
-        return (x + width * (z + length * y)) as usize;
+        Some(x + width * (z + length * y))
     }
 
-    pub fn set_block(&mut self, x: i16, y: i16, z: i16, block_id: u8) {
-        let block = self.coord_to_block_idx(x, y, z);
-        match self.blocks.get_mut(block) {
+    pub fn set_block(&mut self, x: i16, y: i16, z: i16, block_id: u8) -> anyhow::Result<()> {
+        let block = self
+            .coord_to_block_idx(x, y, z)
+            .and_then(|idx| self.blocks.get_mut(idx));
+        match block {
             Some(bid) => *bid = block_id,
-            None => {}
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Block {:?} is outside of the map",
+                    (x, y, z)
+                ))
+            }
         }
+        Ok(())
+    }
+
+    pub fn get_block(&self, x: i16, y: i16, z: i16) -> Option<u8> {
+        self.coord_to_block_idx(x, y, z)
+            .and_then(|idx| self.blocks.get(idx))
+            .copied()
     }
 
-    pub fn get_block(&mut self, x: i16, y: i16, z: i16) -> u8 {
-        let block = self.coord_to_block_idx(x, y, z);
-        match self.blocks.get(block) {
-            Some(bid) => *bid,
-            None => panic!("Cant find this block!"), // or return air
+    /// Schedules a block update the given amount of physics ticks from now.
+    pub fn schedule(&mut self, coords: (i16, i16, i16), delay: u64) {
+        self.pending.insert((self.ticks + delay, coords));
+    }
+
+    /// Bytes held by the blocks and the pending block updates.
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.capacity() + self.pending.len() * std::mem::size_of::<(u64, (i16, i16, i16))>()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Advances the physics tick and takes at most `limit` updates that are due, the rest wait.
+    pub fn due_updates(&mut self, limit: usize) -> Vec<(i16, i16, i16)> {
+        self.ticks += 1;
+        let mut due = Vec::new();
+        while due.len() < limit {
+            match self.pending.first() {
+                Some((tick, _)) if *tick <= self.ticks => {}
+                _ => break,
+            }
+            if let Some((_, coords)) = self.pending.pop_first() {
+                due.push(coords);
+            }
         }
+        due
     }
 
-    pub fn load_world<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    /// Reads a ClassicWorld map, or by the extension of the file an MCGalaxy level (`.lvl`)
+    /// or a level of the original classic server (`.level` or `.dat`).
+    pub fn load_world<P: AsRef<Path>>(path: P, blocks: &BlockRegistry) -> anyhow::Result<Self> {
         // Load file
+        let extension = path.as_ref().extension().and_then(|e| e.to_str());
+        let extension = extension.unwrap_or_default().to_ascii_lowercase();
         let f = File::open(path)?;
         let r = BufReader::new(f);
         let mut gz = bufread::GzDecoder::new(r);
-        let nbt = NBT::read(&mut gz).unwrap();
+        match extension.as_str() {
+            "lvl" => return lvl::read_lvl(&mut gz, blocks),
+            "level" | "dat" => return java_level::read_level(&mut gz),
+            _ => {}
+        }
+        let nbt = NBT::read(&mut gz)?;
 
         // Parse nbt
         let mut width = 1;
@@ -87,71 +153,74 @@ impl World {
         let mut length = 1;
         let mut blocks = Vec::new();
         let mut spawn = (1i16, 1i16, 1i16);
+        let mut meta = ClassicWorldMeta::default();
 
-        if let nbt::Tag::Compound(v) = nbt.tag() {
+        if let nbt::Tag::Compound(v) = nbt.into_tag() {
             for (key, tag) in v {
-                match key.as_str() {
-                    "X" => {
-                        if let nbt::Tag::Short(s) = tag {
-                            width = *s;
-                        }
+                match (key.as_str(), tag) {
+                    ("FormatVersion", nbt::Tag::Byte(_)) => {} // always written as 1
+                    ("X", nbt::Tag::Short(s)) => width = s,
+                    ("Y", nbt::Tag::Short(s)) => height = s,
+                    ("Z", nbt::Tag::Short(s)) => length = s,
+                    ("BlockArray", nbt::Tag::ByteArray(b)) => {
+                        blocks = b.iter().map(|by| *by as u8).collect();
                     }
-                    "Y" => {
-                        if let nbt::Tag::Short(s) = tag {
-                            height = *s;
-                        }
-                    }
-                    "Z" => {
-                        if let nbt::Tag::Short(s) = tag {
-                            length = *s;
-                        }
-                    }
-                    "BlockArray" => {
-                        if let nbt::Tag::ByteArray(b) = tag {
-                            blocks = b.iter().map(|by| *by as u8).collect();
-                        }
-                    }
-                    "Spawn" => {
-                        if let nbt::Tag::Compound(sv) = tag {
-                            for (skey, stag) in sv {
-                                match skey.as_str() {
-                                    "X" => {
-                                        if let nbt::Tag::Short(s) = stag {
-                                            spawn.0 = *s;
-                                        }
-                                    }
-                                    "Y" => {
-                                        if let nbt::Tag::Short(s) = stag {
-                                            spawn.1 = *s;
-                                        }
-                                    }
-                                    "Z" => {
-                                        if let nbt::Tag::Short(s) = stag {
-                                            spawn.2 = *s;
-                                        }
-                                    }
-                                    _ => {}
+                    ("Spawn", nbt::Tag::Compound(sv)) => {
+                        for (skey, stag) in sv {
+                            match (skey.as_str(), stag) {
+                                ("X", nbt::Tag::Short(s)) => spawn.0 = s,
+                                ("Y", nbt::Tag::Short(s)) => spawn.1 = s,
+                                ("Z", nbt::Tag::Short(s)) => spawn.2 = s,
+                                ("H", nbt::Tag::Byte(b)) => meta.spawn_yaw = b as u8,
+                                ("P", nbt::Tag::Byte(b)) => meta.spawn_pitch = b as u8,
+                                (skey, stag) => {
+                                    meta.spawn_extra.insert(skey.to_string(), stag);
                                 }
                             }
                         }
                     }
-                    _ => {
-                        println!("Name: {} - Tag: {:?}", key, tag);
-                    }
+                    (key, tag) => meta.read_tag(key, tag),
                 }
             }
         }
 
+        if width <= 0 || height <= 0 || length <= 0 {
+            return Err(anyhow::anyhow!(
+                "Unsupported world size {}x{}x{}",
+                width,
+                height,
+                length
+            ));
+        }
+        let count = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|c| c.checked_mul(length as usize))
+            .unwrap_or(usize::MAX);
+        if blocks.len() != count {
+            return Err(anyhow::anyhow!(
+                "BlockArray holds {} blocks, expected {} for {}x{}x{}",
+                blocks.len(),
+                count,
+                width,
+                height,
+                length
+            ));
+        }
+
         // TODO(nv): check socket code in classicube
         // 256 - 64 - 256 only half of the world is sent
         // 128 - 64 - 128 works fine
 
         Ok(World {
+            name: String::new(),
             width,
             height,
             length,
             blocks,
             spawn,
+            meta,
+            ticks: 0,
+            pending: BTreeSet::new(),
         })
     }
 
@@ -161,10 +230,8 @@ impl World {
         let w = BufWriter::new(f);
         let mut gz = write::GzEncoder::new(w, Default::default());
 
-        // TODO(nv): probably reuse loaded nbt from cw file -- save inside world
-
-        // Create nbt
-        let mut m = HashMap::<String, nbt::Tag>::new();
+        // Create nbt, starting with the tags read from the file
+        let mut m = self.meta.to_tags();
         m.insert("FormatVersion".into(), nbt::Tag::Byte(1));
         m.insert("X".into(), nbt::Tag::Short(self.width));
         m.insert("Y".into(), nbt::Tag::Short(self.height));
@@ -174,10 +241,12 @@ impl World {
             nbt::Tag::ByteArray(self.blocks.iter().map(|b| *b as i8).collect()),
         );
 
-        let mut sm = HashMap::<String, nbt::Tag>::new();
+        let mut sm = self.meta.spawn_extra.clone();
         sm.insert("X".into(), nbt::Tag::Short(self.spawn.0));
         sm.insert("Y".into(), nbt::Tag::Short(self.spawn.1));
         sm.insert("Z".into(), nbt::Tag::Short(self.spawn.2));
+        sm.insert("H".into(), nbt::Tag::Byte(self.meta.spawn_yaw as i8));
+        sm.insert("P".into(), nbt::Tag::Byte(self.meta.spawn_pitch as i8));
 
         m.insert("Spawn".into(), nbt::Tag::Compound(sm));
 
@@ -185,9 +254,15 @@ impl World {
 
         // Write into file
         nbt.write(&mut gz)?;
+        gz.finish()?.flush()?;
         Ok(())
     }
 
+    /// Block players spawn at.
+    pub fn set_spawn(&mut self, spawn: (i16, i16, i16)) {
+        self.spawn = spawn;
+    }
+
     pub fn spawning_point(&mut self) -> (i16, i16, i16) {
         // Convert world coords to player's
         let world_x = (self.spawn.0 as f64 * 32.0) as i16;
@@ -196,22 +271,41 @@ impl World {
         (world_x, world_y, world_z)
     }
 
-    pub fn gzip_world(&mut self) -> anyhow::Result<Vec<u8>> {
+    /// Blocks as sent to a client, the ones it doesn't know are replaced by their fallback.
+    pub fn gzip_world(
+        &mut self,
+        blocks: &BlockRegistry,
+        custom_blocks: bool,
+    ) -> anyhow::Result<Vec<u8>> {
         let mut gzipper = write::GzEncoder::new(Vec::new(), flate2::Compression::default());
         let world_size = self.width as i32 * self.height as i32 * self.length as i32;
-        gzipper.write(&world_size.to_be_bytes())?; // world size
-        gzipper.write(&self.blocks)?;
+        gzipper.write_all(&world_size.to_be_bytes())?; // world size
+        if custom_blocks {
+            gzipper.write_all(&self.blocks)?;
+        } else {
+            let fallbacks: Vec<u8> = self
+                .blocks
+                .iter()
+                .map(|b| blocks.for_client(*b, false))
+                .collect();
+            gzipper.write_all(&fallbacks)?;
+        }
 
         Ok(gzipper.finish()?)
     }
 
     // TODO(nv): move outside of world?
-    pub fn send_world<W: Write>(&mut self, writer: &mut W) -> anyhow::Result<()> {
+    pub fn send_world<W: Write>(
+        &mut self,
+        writer: &mut W,
+        blocks: &BlockRegistry,
+        custom_blocks: bool,
+    ) -> anyhow::Result<()> {
         // Init level transmition
         packets::level_init(writer, ServerPacket::LevelInit)?;
 
         // Algorithm to send bytes in chunk
-        let gblocks = self.gzip_world()?;
+        let gblocks = self.gzip_world(blocks, custom_blocks)?;
         let total_bytes = gblocks.len();
         let mut current_bytes = 0;
         while current_bytes < total_bytes {
@@ -251,3 +345,99 @@ impl World {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nbt::Tag;
+    use std::collections::HashMap;
+
+    fn compound(tags: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(tags.into_iter().map(|(k, t)| (k.to_string(), t)).collect())
+    }
+
+    fn write_gzip(path: &Path, nbt: &NBT) {
+        let mut gz = write::GzEncoder::new(File::create(path).unwrap(), Default::default());
+        nbt.write(&mut gz).unwrap();
+        gz.finish().unwrap();
+    }
+
+    fn read_gzip(path: &Path) -> Tag {
+        let mut gz = bufread::GzDecoder::new(BufReader::new(File::open(path).unwrap()));
+        NBT::read(&mut gz).unwrap().into_tag()
+    }
+
+    #[test]
+    fn saving_keeps_the_tags_of_the_file() {
+        let root = compound(vec![
+            ("FormatVersion", Tag::Byte(1)),
+            ("X", Tag::Short(2)),
+            ("Y", Tag::Short(1)),
+            ("Z", Tag::Short(2)),
+            ("BlockArray", Tag::ByteArray(vec![1, 2, 3, 4])),
+            ("UUID", Tag::ByteArray(vec![7; 16])),
+            (
+                "Spawn",
+                compound(vec![
+                    ("X", Tag::Short(1)),
+                    ("Y", Tag::Short(0)),
+                    ("Z", Tag::Short(1)),
+                    ("H", Tag::Byte(64)),
+                    ("P", Tag::Byte(0)),
+                    ("Extra", Tag::Int(5)),
+                ]),
+            ),
+            (
+                "CreatedBy",
+                compound(vec![
+                    ("Service", Tag::String("ClassiCube".into())),
+                    ("Username", Tag::String("notch".into())),
+                    ("Extra", Tag::Int(1)),
+                ]),
+            ),
+            (
+                "MapGenerator",
+                compound(vec![
+                    ("Software", Tag::String("other".into())),
+                    ("MapGeneratorName", Tag::String("".into())),
+                    ("Seed", Tag::Long(42)),
+                ]),
+            ),
+            ("TimeCreated", Tag::Long(1000)),
+            (
+                "Metadata",
+                compound(vec![("CPE", Tag::Compound(HashMap::new()))]),
+            ),
+            ("Unknown", Tag::List(0x03, vec![Tag::Int(1), Tag::Int(2)])),
+        ]);
+
+        let dir = std::env::temp_dir();
+        let original = dir.join(format!("qubiq-{}-original.cw", std::process::id()));
+        let saved = dir.join(format!("qubiq-{}-saved.cw", std::process::id()));
+        write_gzip(&original, &NBT::new("ClassicWorld", root.clone()));
+
+        let mut world = World::load_world(&original, &BlockRegistry::new()).unwrap();
+        world.name = String::from("main"); // must not end up in the file
+        world.save_world(&saved).unwrap();
+        let tag = read_gzip(&saved);
+        let _ = std::fs::remove_file(&original);
+        let _ = std::fs::remove_file(&saved);
+
+        assert_eq!(tag, root);
+    }
+
+    #[test]
+    fn rejects_negative_sizes() {
+        let root = compound(vec![
+            ("X", Tag::Short(-1)),
+            ("Y", Tag::Short(-1)),
+            ("Z", Tag::Short(-1)),
+            ("BlockArray", Tag::ByteArray(vec![0])),
+        ]);
+        let path = std::env::temp_dir().join(format!("qubiq-{}-negative.cw", std::process::id()));
+        write_gzip(&path, &NBT::new("ClassicWorld", root));
+        let result = World::load_world(&path, &BlockRegistry::new());
+        let _ = std::fs::remove_file(&path);
+        assert!(result.is_err());
+    }
+}
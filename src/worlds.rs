@@ -0,0 +1,176 @@
+use crate::block::BlockRegistry;
+use crate::World;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const FORMATS: [&str; 4] = ["cw", "lvl", "level", "dat"]; // read in this order when several exist
+
+/// Every known world by name, each one is saved as `<name>.cw` in the maps directory.
+/// Maps in the formats of other servers are listed too and converted to `.cw` on their first save.
+/// Worlds other than the main one are loaded on the first visit and unloaded once left idle.
+pub struct Worlds {
+    pub main: String, // world players join first, always loaded
+    directory: PathBuf,
+    worlds: BTreeMap<String, Option<World>>, // None while unloaded
+    unload_after: Option<Duration>,          // idle time before a world is unloaded
+    idle: BTreeMap<String, Instant>,         // loaded worlds without players since then
+}
+
+impl Worlds {
+    /// Holds the main world saved at `path` and lists every other map found next to it.
+    pub fn new(mut main: World, path: &str, unload_after: u64) -> anyhow::Result<Self> {
+        let path = Path::new(path);
+        let directory = match path.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => PathBuf::new(),
+        };
+        main.name = world_name(path).unwrap_or_else(|| String::from("main"));
+
+        let mut worlds = Worlds {
+            main: main.name.clone(),
+            directory,
+            worlds: BTreeMap::new(),
+            unload_after: match unload_after {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            idle: BTreeMap::new(),
+        };
+        worlds.worlds.insert(main.name.clone(), Some(main));
+
+        // The directory may not exist before the main world is saved for the first time
+        let entries = match std::fs::read_dir(&worlds.directory) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(worlds),
+        };
+        for entry in entries {
+            let file = entry?.path();
+            let name = match world_name(&file) {
+                Some(name)
+                    if file
+                        .extension()
+                        .is_some_and(|e| FORMATS.iter().any(|f| e == *f)) =>
+                {
+                    name
+                }
+                _ => continue,
+            };
+            if worlds.find(&name).is_none() {
+                worlds.worlds.insert(name, None);
+            }
+        }
+
+        Ok(worlds)
+    }
+
+    /// Name of a known world, ignoring case.
+    pub fn find(&self, name: &str) -> Option<String> {
+        self.worlds
+            .keys()
+            .find(|n| n.eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    /// A loaded world, None if it is unknown or unloaded.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut World> {
+        self.worlds.get_mut(name).and_then(|world| world.as_mut())
+    }
+
+    /// A world, read from its file first if it is unloaded.
+    pub fn load(&mut self, name: &str, blocks: &BlockRegistry) -> anyhow::Result<&mut World> {
+        let path = FORMATS
+            .iter()
+            .map(|format| self.directory.join(format!("{}.{}", name, format)))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| self.directory.join(format!("{}.cw", name)));
+        let slot = match self.worlds.get_mut(name) {
+            Some(slot) => slot,
+            None => return Err(anyhow::anyhow!("Unknown world: {}", name)),
+        };
+        if slot.is_none() {
+            let mut world = World::load_world(&path, blocks)?;
+            world.name = name.to_string();
+            println!("Loaded world {} from {}", name, path.display());
+            *slot = Some(world);
+        }
+        match slot {
+            Some(world) => Ok(world),
+            None => Err(anyhow::anyhow!("Unknown world: {}", name)),
+        }
+    }
+
+    /// Every known world, with the loaded ones.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Option<&World>)> {
+        self.worlds
+            .iter()
+            .map(|(name, world)| (name, world.as_ref()))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.worlds.keys()
+    }
+
+    /// Loaded worlds only.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut World> {
+        self.worlds.values_mut().filter_map(|world| world.as_mut())
+    }
+
+    pub fn save_all(&mut self) -> anyhow::Result<()> {
+        if !self.directory.as_os_str().is_empty() {
+            std::fs::create_dir_all(&self.directory)?;
+        }
+        let directory = self.directory.clone();
+        for world in self.iter_mut() {
+            world.save_world(directory.join(format!("{}.cw", world.name)))?;
+        }
+        Ok(())
+    }
+
+    /// Saves and unloads the worlds no one in `occupied` played in for long enough.
+    pub fn unload_idle(&mut self, occupied: &[String]) {
+        let unload_after = match self.unload_after {
+            Some(unload_after) => unload_after,
+            None => return,
+        };
+
+        let now = Instant::now();
+        for (name, slot) in self.worlds.iter_mut() {
+            if *name == self.main || slot.is_none() || occupied.contains(name) {
+                self.idle.remove(name);
+                continue;
+            }
+            let since = *self.idle.entry(name.clone()).or_insert(now);
+            if now.duration_since(since) < unload_after {
+                continue;
+            }
+
+            // Changes are kept even with autosave off, the blocks would be lost otherwise
+            if let Some(world) = slot {
+                let path = self.directory.join(format!("{}.cw", name));
+                if let Err(e) = world.save_world(&path) {
+                    println!("Keeping world {} loaded, saving failed: {}", name, e);
+                    continue;
+                }
+            }
+            *slot = None;
+            self.idle.remove(name);
+            println!("Unloaded idle world {}", name);
+        }
+    }
+
+    /// Time left until the next idle world is unloaded, None if none is waiting.
+    pub fn until_unload(&self) -> Option<Duration> {
+        let unload_after = self.unload_after?;
+        self.idle
+            .values()
+            .map(|since| unload_after.saturating_sub(since.elapsed()))
+            .min()
+    }
+}
+
+fn world_name(path: &Path) -> Option<String> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(String::from)
+}